@@ -0,0 +1,157 @@
+use std::{io::Cursor, time::Duration};
+use anyhow::{Result, anyhow};
+use image::{imageops::FilterType, io::Reader};
+use reqwest::{Client, Url};
+
+/// Limits on images rendered as IRC colour art
+#[derive(Debug, Clone)]
+pub struct ArtConfig {
+    /// Maximum width, in characters
+    pub width: u32,
+    /// Maximum height, in lines
+    pub height: u32,
+    /// Maximum size of a single rendered line, in bytes
+    pub line_bytes: usize,
+}
+
+impl Default for ArtConfig {
+    fn default() -> Self {
+        ArtConfig { width: 40, height: 20, line_bytes: 400 }
+    }
+}
+
+/// Largest image we are willing to download
+const MAX_DOWNLOAD: usize = 8 * 1024 * 1024;
+
+/// Largest image we are willing to decode, in pixels. Small compressed
+/// files can decode to huge images.
+const MAX_PIXELS: u64 = 16 * 1024 * 1024;
+
+const UPPER_HALF_BLOCK: char = '\u{2580}';
+
+/// mIRC extended colours 16 to 98. Colours 0-15 are left out,
+/// since most clients let the user theme them.
+const PALETTE: [u32; 83] = [
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b,
+    0xff0000, 0xff8c00, 0xffff00, 0xb2ff00, 0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9, 0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc,
+    0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2, 0xffffff,
+];
+
+/// Colour 99 means "default colour" to clients supporting extended colours
+const DEFAULT_COLOUR: u8 = 99;
+
+fn nearest_colour(rgb: &[u8]) -> u8 {
+    let distance = |c: u32| {
+        let dr = ((c >> 16) & 0xff) as i32 - rgb[0] as i32;
+        let dg = ((c >> 8) & 0xff) as i32 - rgb[1] as i32;
+        let db = (c & 0xff) as i32 - rgb[2] as i32;
+        dr*dr + dg*dg + db*db
+    };
+
+    let (idx, _) = PALETTE.iter().enumerate()
+        .min_by_key(|(_, c)| distance(**c))
+        .unwrap();
+
+    16 + idx as u8
+}
+
+/// Render an image as lines of upper half blocks, where each character cell
+/// carries two pixels: the top one as foreground, the bottom one as background.
+pub fn render(data: &[u8], config: &ArtConfig) -> Result<Vec<String>> {
+    let (width, height) = Reader::new(Cursor::new(data)).with_guessed_format()?.into_dimensions()?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(anyhow!("Image of {}x{} pixels is too large", width, height))
+    }
+
+    let img = Reader::new(Cursor::new(data)).with_guessed_format()?.decode()?
+        .resize(config.width, config.height * 2, FilterType::Triangle)
+        .to_rgb8();
+    let (width, height) = img.dimensions();
+
+    let mut lines = Vec::new();
+    for y in (0..height).step_by(2) {
+        let mut line = String::new();
+        let mut current = None;
+
+        for x in 0..width {
+            let fg = nearest_colour(&img.get_pixel(x, y).0);
+            let bg = if y + 1 < height { nearest_colour(&img.get_pixel(x, y + 1).0) } else { DEFAULT_COLOUR };
+
+            let cell = if current == Some((fg, bg)) {
+                UPPER_HALF_BLOCK.to_string()
+            } else {
+                format!("\x03{:02},{:02}{}", fg, bg, UPPER_HALF_BLOCK)
+            };
+
+            // keep room for the final reset code
+            if line.len() + cell.len() + 1 > config.line_bytes {
+                break;
+            }
+            line += &cell;
+            current = Some((fg, bg));
+        }
+
+        line.push('\x0f');
+        lines.push(line);
+    }
+
+    Ok(lines)
+}
+
+/// Downloads attachments through the user's Rocket session and renders them
+#[derive(Clone)]
+pub struct Renderer {
+    client: Client,
+    base: Url,
+    user_id: String,
+    token: String,
+    config: ArtConfig,
+}
+
+impl Renderer {
+
+    pub fn new(server: &str, user_id: String, token: String, config: ArtConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let base = Url::parse(&format!("https://{}/", server))?;
+        Ok(Renderer { client, base, user_id, token, config })
+    }
+
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let url = self.base.join(url)?;
+        let mut request = self.client.get(url.clone());
+
+        // Never leak credentials to third-party hosts
+        if url.host_str() == self.base.host_str() {
+            request = request
+                .header("X-User-Id", &self.user_id)
+                .header("X-Auth-Token", &self.token);
+        }
+
+        let mut response = request.send().await?.error_for_status()?;
+        if response.content_length().map_or(false, |len| len > MAX_DOWNLOAD as u64) {
+            return Err(anyhow!("Image larger than {} bytes", MAX_DOWNLOAD))
+        }
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            if data.len() > MAX_DOWNLOAD {
+                return Err(anyhow!("Image larger than {} bytes", MAX_DOWNLOAD))
+            }
+        }
+        Ok(data)
+    }
+
+    pub async fn render_url(&self, url: &str) -> Result<Vec<String>> {
+        let data = self.download(url).await?;
+        let config = self.config.clone();
+        tokio::task::spawn_blocking(move || render(&data, &config)).await?
+    }
+
+}
@@ -0,0 +1,172 @@
+use rasta::{Credentials, LoginError};
+
+/// Credentials presented by an IRC client
+#[derive(Clone, PartialEq)]
+pub enum Login {
+    /// Rocket personal access token
+    Token(String),
+    /// Rocket username (or email) and password, with a two-factor code if needed
+    Password { user: String, password: String, totp: Option<String> },
+}
+
+impl std::fmt::Debug for Login {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Login::Token(_) => write!(f, "Token(..)"),
+            Login::Password { user, .. } => write!(f, "Password({}, ..)", user),
+        }
+    }
+}
+
+impl Login {
+
+    /// Parse a server password: either a personal access token, or `username:password`
+    pub fn parse(pass: &str) -> Self {
+        let mut fields = pass.splitn(2, ':');
+        match (fields.next(), fields.next()) {
+            (Some(user), Some(password)) => Login::Password { user: user.to_string(), password: password.to_string(), totp: None },
+            _ => Login::Token(pass.to_string()),
+        }
+    }
+
+    /// The same login with a trailing `:123456` taken off the password as the
+    /// two-factor code. Only tried when Rocket asks for a code, since passwords
+    /// may end like this too.
+    pub fn split_totp(&self) -> Option<Self> {
+        match self {
+            Login::Password { user, password, totp: None } => {
+                let idx = password.rfind(':')?;
+                let code = &password[idx+1..];
+                if (6..=8).contains(&code.len()) && code.chars().all(|c| c.is_ascii_digit()) {
+                    Some(Login::Password { user: user.clone(), password: password[..idx].to_string(), totp: Some(code.to_string()) })
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn with_totp(self, code: String) -> Self {
+        match self {
+            Login::Password { user, password, .. } => Login::Password { user, password, totp: Some(code) },
+            token => token,
+        }
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        match self {
+            Login::Token(token) => Credentials::from(token.clone()),
+            Login::Password { user, password, totp } => {
+                let credentials = Credentials::password(user.clone(), password.clone());
+                match totp {
+                    Some(code) => credentials.with_totp(code.clone()),
+                    None => credentials,
+                }
+            },
+        }
+    }
+
+}
+
+/// Why the backend refused a login
+#[derive(Debug)]
+pub enum Refusal {
+    /// The password is right, but a two-factor code is needed
+    TotpRequired,
+    TotpInvalid,
+    Other(String),
+}
+
+impl From<LoginError> for Refusal {
+    fn from(e: LoginError) -> Self {
+        match e.error.as_str() {
+            "totp-required" => Refusal::TotpRequired,
+            "totp-invalid" => Refusal::TotpInvalid,
+            _ => Refusal::Other(e.reason.unwrap_or(e.error)),
+        }
+    }
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::TotpRequired => write!(f, "Two-factor authentication code required"),
+            Refusal::TotpInvalid => write!(f, "Invalid two-factor authentication code"),
+            Refusal::Other(reason) => write!(f, "Backend server rejected credentials: {}", reason),
+        }
+    }
+}
+
+/// Decode a SASL PLAIN payload (`authzid \0 authcid \0 password`).
+/// With an empty username, or the username "token", the password is
+/// taken as a personal access token.
+fn decode_plain(payload: &str) -> Option<Login> {
+    let decoded = String::from_utf8(base64::decode(payload).ok()?).ok()?;
+    let mut fields = decoded.split('\0');
+    let (_authzid, authcid, password) = (fields.next()?, fields.next()?, fields.next()?);
+
+    if password.is_empty() {
+        return None
+    }
+
+    if authcid.is_empty() || authcid.eq_ignore_ascii_case("token") {
+        Some(Login::Token(password.to_string()))
+    } else {
+        Some(Login::Password { user: authcid.to_string(), password: password.to_string(), totp: None })
+    }
+}
+
+/// Outcome of one AUTHENTICATE line
+pub enum SaslStep {
+    /// Send an empty challenge (`AUTHENTICATE +`)
+    Challenge,
+    /// A 400-byte chunk was received, wait for the rest
+    More,
+    /// The client sent its credentials
+    Done(Login),
+    Unsupported,
+    Aborted,
+    Invalid,
+}
+
+/// Server side of a SASL PLAIN exchange
+#[derive(Default)]
+pub struct Sasl {
+    started: bool,
+    buffer: String,
+}
+
+impl Sasl {
+
+    pub fn step(&mut self, data: &str) -> SaslStep {
+        if data == "*" {
+            *self = Sasl::default();
+            return SaslStep::Aborted
+        }
+
+        if !self.started {
+            return if data.eq_ignore_ascii_case("PLAIN") {
+                self.started = true;
+                SaslStep::Challenge
+            } else {
+                SaslStep::Unsupported
+            }
+        }
+
+        if data != "+" {
+            self.buffer += data;
+        }
+        if data.len() == 400 {
+            return SaslStep::More
+        }
+
+        let payload = std::mem::take(&mut self.buffer);
+        *self = Sasl::default();
+        match decode_plain(&payload) {
+            Some(login) => SaslStep::Done(login),
+            None => SaslStep::Invalid,
+        }
+    }
+
+}
@@ -0,0 +1,92 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+use futures::channel::mpsc::UnboundedSender;
+use tokio::sync::Mutex as AsyncMutex;
+use irc_proto::{Command, Message, message::Tag};
+use crate::{caps, proxy::Event};
+
+/// A running Rocket session, as seen by IRC connections
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub events: UnboundedSender<Event>,
+    /// Rocket username the session is logged in as
+    pub account: String,
+}
+
+/// Where the session of a user is kept, if it is running
+pub type Slot = Arc<AsyncMutex<Option<SessionHandle>>>;
+
+/// Rocket sessions kept alive between IRC connections, indexed by Rocket user ID
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Slot>>,
+}
+
+impl Sessions {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Slot of the session of a user. Its lock is held while looking for a
+    /// running session and starting one, so that concurrent logins of the same
+    /// user end up sharing one session.
+    pub fn slot(&self, userid: &str) -> Slot {
+        self.sessions.lock().unwrap().entry(userid.into()).or_default().clone()
+    }
+
+}
+
+impl SessionHandle {
+
+    pub fn is_running(&self) -> bool {
+        !self.events.is_closed()
+    }
+
+}
+
+/// Messages received while no client was attached, replayed on the next attach.
+/// Only conversation is kept, since channel state is sent again on attach anyway.
+pub struct Backlog {
+    messages: VecDeque<Message>,
+    capacity: usize,
+}
+
+impl Backlog {
+
+    pub fn new(capacity: usize) -> Self {
+        Backlog { messages: VecDeque::new(), capacity }
+    }
+
+    pub fn push(&mut self, mut msg: Message) {
+        match msg.command {
+            Command::PRIVMSG(..) | Command::NOTICE(..) => {},
+            _ => return,
+        }
+
+        if self.capacity == 0 {
+            return
+        }
+
+        let tags = msg.tags.get_or_insert_with(Vec::new);
+        if !tags.iter().any(|Tag(key, _)| key == "time") {
+            tags.push(caps::time_tag(None));
+        }
+
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(msg);
+    }
+
+    /// Whether the message with this ID is waiting to be replayed
+    pub fn contains(&self, msgid: &str) -> bool {
+        self.messages.iter()
+            .flat_map(|msg| msg.tags.iter().flatten())
+            .any(|Tag(key, value)| key == "msgid" && value.as_deref() == Some(msgid))
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item=Message> + '_ {
+        self.messages.drain(..)
+    }
+
+}
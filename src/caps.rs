@@ -0,0 +1,126 @@
+use std::collections::BTreeSet;
+use chrono::{TimeZone, Utc};
+use irc_proto::{CapSubCommand, Command, Message, message::Tag};
+use serde_json::Value;
+use crate::multiline;
+
+/// IRCv3 capabilities the bridge knows how to honour. Clients rely on what
+/// is advertised here, so a capability is only added once the proxy
+/// implements it.
+pub const SUPPORTED: [&str; 10] = ["account-tag", "away-notify", "batch", "draft/chathistory", "draft/message-redaction",
+    "draft/multiline", "echo-message", "message-tags", "sasl", "server-time"];
+
+/// Capabilities enabled by a client during negotiation
+#[derive(Debug, Clone, Default)]
+pub struct Caps {
+    enabled: BTreeSet<String>,
+}
+
+/// `time` tag for a Rocket timestamp in milliseconds, or for the current time
+pub fn time_tag(millis: Option<i64>) -> Tag {
+    let time = match millis {
+        Some(millis) => Utc.timestamp_millis(millis),
+        None => Utc::now(),
+    };
+    Tag("time".into(), Some(time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()))
+}
+
+/// IRCv3 tags describing a Rocket message: time, sender account, and
+/// optionally the message ID (which must only be sent once)
+pub fn message_tags(message: &Value, with_id: bool) -> Option<Vec<Tag>> {
+    let mut tags = vec![time_tag(message["ts"]["$date"].as_i64())];
+    if let Some(account) = message["u"]["username"].as_str() {
+        tags.push(Tag("account".into(), Some(account.into())));
+    }
+    if let (true, Some(id)) = (with_id, message["_id"].as_str()) {
+        tags.push(Tag("msgid".into(), Some(id.into())));
+    }
+    Some(tags)
+}
+
+impl Caps {
+
+    pub fn has(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    fn list(&self) -> String {
+        self.enabled.iter().cloned().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Apply a CAP REQ. Requests are atomic: if any capability is
+    /// unknown, nothing changes and false is returned.
+    pub fn request(&mut self, req: &str) -> bool {
+        let known = req.split_whitespace()
+            .all(|cap| SUPPORTED.contains(&cap.trim_start_matches('-')));
+        if !known {
+            return false
+        }
+
+        for cap in req.split_whitespace() {
+            match cap.strip_prefix('-') {
+                Some(cap) => { self.enabled.remove(cap); },
+                None => { self.enabled.insert(cap.to_string()); },
+            }
+        }
+        true
+    }
+
+    /// Reply to a CAP subcommand. END is left to the caller, since
+    /// it only matters during registration.
+    pub fn handle(&mut self, nick: &str, sub: CapSubCommand, arg: Option<String>) -> Option<Command> {
+        let nick = Some(nick.to_string());
+        match sub {
+            CapSubCommand::LS => {
+                // CAP LS 302 and later carry capability values
+                let v302 = arg.and_then(|v| v.parse::<u32>().ok()).map_or(false, |v| v >= 302);
+                let list = SUPPORTED.iter()
+                    .map(|cap| match (*cap, v302) {
+                        ("sasl", true) => "sasl=PLAIN".to_string(),
+                        ("draft/multiline", true) => format!("draft/multiline={}", multiline::limits()),
+                        (cap, _) => cap.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Some(Command::CAP(nick, CapSubCommand::LS, Some(list), None))
+            },
+            CapSubCommand::LIST => Some(Command::CAP(nick, CapSubCommand::LIST, Some(self.list()), None)),
+            CapSubCommand::REQ => {
+                let req = arg.unwrap_or_default();
+                let reply = if self.request(&req) { CapSubCommand::ACK } else { CapSubCommand::NAK };
+                Some(Command::CAP(nick, reply, Some(req), None))
+            },
+            _ => None,
+        }
+    }
+
+    fn allows_tag(&self, key: &str) -> bool {
+        match key {
+            "time" => self.has("server-time"),
+            "account" => self.has("account-tag"),
+            "batch" => self.has("batch"),
+            "draft/multiline-concat" => self.has("draft/multiline"),
+            _ => self.has("message-tags"),
+        }
+    }
+
+    /// Adapt an outgoing message to this client: strip the tags it did not
+    /// negotiate, and drop messages it did not ask for at all.
+    pub fn filter(&self, mut msg: Message) -> Option<Message> {
+        if let Command::AWAY(_) = msg.command {
+            if !self.has("away-notify") {
+                return None
+            }
+        }
+
+        if let Some(tags) = msg.tags.take() {
+            let tags: Vec<Tag> = tags.into_iter()
+                .filter(|Tag(key, _)| self.allows_tag(key))
+                .collect();
+            msg.tags = if tags.is_empty() { None } else { Some(tags) };
+        }
+
+        Some(msg)
+    }
+
+}
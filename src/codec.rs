@@ -0,0 +1,150 @@
+//! IRC codec for client connections. irc-proto drops the field list of WHOX
+//! requests (`WHO <mask> %<fields>[,<token>]`), and parses BATCH in a way that
+//! loses the case of its type, so these are passed on as raw commands instead.
+use bytes::BytesMut;
+use irc_proto::{Command, IrcCodec, Message, error::ProtocolError};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub struct Codec {
+    inner: IrcCodec,
+}
+
+impl Codec {
+
+    pub fn new(label: &str) -> Result<Self, ProtocolError> {
+        Ok(Codec { inner: IrcCodec::new(label)? })
+    }
+
+}
+
+/// Command and parameters of a line, without its tags and prefix
+fn words(line: &str) -> Vec<String> {
+    let mut rest = line.trim_end_matches(|c| c == '\r' || c == '\n');
+    let mut words: Vec<String> = vec![];
+    loop {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            return words
+        }
+        let end = rest.find(' ').unwrap_or(rest.len());
+        match rest.strip_prefix(':') {
+            Some(trailing) if !words.is_empty() => {
+                words.push(trailing.into());
+                return words
+            },
+            // prefix
+            Some(_) => {},
+            None if words.is_empty() && rest.starts_with('@') => {},
+            None => words.push(rest[..end].into()),
+        }
+        rest = &rest[end..];
+    }
+}
+
+/// Mask and fields of a WHOX request line
+fn whox(words: &[String]) -> Option<Vec<String>> {
+    match words {
+        [_, mask, fields, ..] if fields.starts_with('%') => Some(vec![mask.clone(), fields.clone()]),
+        _ => None,
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Message;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        let line = src.iter().position(|b| *b == b'\n')
+            .map(|end| String::from_utf8_lossy(&src[..end]).into_owned());
+        let msg = self.inner.decode(src)?;
+        let words = line.as_deref().map(words).unwrap_or_default();
+        Ok(msg.map(|msg| match &msg.command {
+            Command::WHO(..) => match whox(&words) {
+                Some(args) => Message { command: Command::Raw("WHO".into(), args), ..msg },
+                None => msg,
+            },
+            Command::BATCH(..) if !words.is_empty() => Message { command: Command::Raw("BATCH".into(), words[1..].to_vec()), ..msg },
+            _ => msg,
+        }))
+    }
+}
+
+impl Encoder<Message> for Codec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        self.inner.encode(msg, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multiline::Incoming;
+    use irc_proto::message::Tag;
+
+    fn decode_all(input: &str) -> Vec<Message> {
+        let mut codec = Codec::new("utf8").unwrap();
+        let mut src = BytesMut::from(input);
+        let mut messages = vec![];
+        while let Some(msg) = codec.decode(&mut src).unwrap() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[test]
+    fn split_words() {
+        assert_eq!(words("@time=x :nick!u@h PRIVMSG #c :hello world\r\n"), vec!["PRIVMSG", "#c", "hello world"]);
+        assert_eq!(words("BATCH -x"), vec!["BATCH", "-x"]);
+        assert_eq!(words("WHO #c %tnf,42"), vec!["WHO", "#c", "%tnf,42"]);
+    }
+
+    #[test]
+    fn whox_is_raw() {
+        let messages = decode_all("WHO #c %tnf,42\r\nWHO #c\r\n");
+        assert_eq!(messages[0].command, Command::Raw("WHO".into(), vec!["#c".into(), "%tnf,42".into()]));
+        assert!(matches!(messages[1].command, Command::WHO(..)));
+    }
+
+    #[test]
+    fn multiline_batch() {
+        let messages = decode_all(concat!(
+            "BATCH +x draft/multiline #c\r\n",
+            "@batch=x PRIVMSG #c :first line\r\n",
+            "@batch=x PRIVMSG #c :second \r\n",
+            "@batch=x;draft/multiline-concat PRIVMSG #c :line\r\n",
+            "BATCH -x\r\n",
+        ));
+
+        let mut batch = None;
+        let mut complete = None;
+        for msg in messages {
+            let tag = |name: &str| msg.tags.iter().flatten().find(|Tag(key, _)| key == name).map(|Tag(_, value)| value.clone());
+            match &msg.command {
+                Command::Raw(cmd, args) if cmd == "BATCH" => match &args[..] {
+                    [reference, kind, target] => {
+                        assert_eq!(reference, "+x");
+                        assert_eq!(kind, "draft/multiline");
+                        batch = Some(Incoming::new("x".into(), target.clone(), None));
+                    },
+                    [reference] => {
+                        assert_eq!(reference, "-x");
+                        complete = batch.take();
+                    },
+                    other => panic!("unexpected BATCH {:?}", other),
+                },
+                Command::PRIVMSG(_, text) => {
+                    assert_eq!(tag("batch"), Some(Some("x".into())));
+                    let concat = tag("draft/multiline-concat").is_some();
+                    batch.as_mut().unwrap().push(text, concat).unwrap();
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        let complete = complete.unwrap();
+        assert_eq!(complete.target, "#c");
+        assert_eq!(complete.text, "first line\nsecond line");
+    }
+}
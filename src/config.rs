@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use anyhow::{Result, anyhow};
+use crate::{art::ArtConfig, naming::Prefixes};
+
+/// Runtime configuration, built from the command line
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: String,
+    pub backend: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Render image attachments as colour art, if set
+    pub art: Option<ArtConfig>,
+    /// Keep Rocket sessions alive after the IRC client disconnects
+    pub bouncer: bool,
+    /// Number of messages kept for detached bouncer sessions
+    pub backlog: usize,
+    /// Number of past messages replayed when joining a channel
+    pub history: usize,
+    /// Only replay the messages not read yet
+    pub history_unread: bool,
+    /// Marks thread references (`>>abcd`) in plain text, empty to disable
+    pub thread_marker: String,
+    /// Channel prefix of each kind of room
+    pub prefixes: Prefixes,
+    /// Create rooms when joining channels that do not exist
+    pub create_channels: bool,
+    /// Convert between markdown and IRC formatting codes, until a user
+    /// changes it for their session with FORMATTING
+    pub formatting: bool,
+}
+
+fn value(args: &mut impl Iterator<Item=String>, flag: &str) -> Result<String> {
+    args.next().ok_or(anyhow!("Option {} requires a value", flag))
+}
+
+fn prefix(args: &mut impl Iterator<Item=String>, flag: &str) -> Result<char> {
+    let value = value(args, flag)?;
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_punctuation() && c != ',' && c != ':' => Ok(c),
+        _ => Err(anyhow!("Option {} requires a single punctuation character", flag)),
+    }
+}
+
+impl Config {
+
+    pub fn from_args(mut args: impl Iterator<Item=String>) -> Result<Self> {
+        let mut positional = Vec::new();
+        let (mut tls_cert, mut tls_key) = (None, None);
+        let (mut images, mut art) = (false, ArtConfig::default());
+        let (mut bouncer, mut backlog) = (false, 2000);
+        let (mut history, mut history_unread) = (0, false);
+        let mut thread_marker = ">>".to_string();
+        let mut prefixes = Prefixes::default();
+        let mut create_channels = false;
+        let mut formatting = true;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tls-cert" => { tls_cert = Some(value(&mut args, &arg)?.into()) },
+                "--tls-key" => { tls_key = Some(value(&mut args, &arg)?.into()) },
+                "--images" => { images = true },
+                "--image-width" => { art.width = value(&mut args, &arg)?.parse()? },
+                "--image-height" => { art.height = value(&mut args, &arg)?.parse()? },
+                "--image-line-bytes" => { art.line_bytes = value(&mut args, &arg)?.parse()? },
+                "--bouncer" => { bouncer = true },
+                "--backlog" => { backlog = value(&mut args, &arg)?.parse()? },
+                "--history" => { history = value(&mut args, &arg)?.parse()? },
+                "--history-unread" => { history_unread = true },
+                "--thread-marker" => { thread_marker = value(&mut args, &arg)? },
+                "--prefix-public" => { prefixes.public = prefix(&mut args, &arg)? },
+                "--prefix-private" => { prefixes.private = prefix(&mut args, &arg)? },
+                "--prefix-discussion" => { prefixes.discussion = prefix(&mut args, &arg)? },
+                "--prefix-team" => { prefixes.team = prefix(&mut args, &arg)? },
+                "--create-channels" => { create_channels = true },
+                "--no-formatting" => { formatting = false },
+                flag if flag.starts_with("--") => return Err(anyhow!("Unknown option {}", flag)),
+                _ => positional.push(arg),
+            }
+        }
+
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(anyhow!("--tls-cert and --tls-key must be used together"))
+        }
+
+        let art = if images { Some(art) } else { None };
+
+        let mut positional = positional.into_iter();
+        match (positional.next(), positional.next(), positional.next()) {
+            (Some(bind), Some(backend), None) => Ok(Config { bind, backend, tls_cert, tls_key, art, bouncer, backlog, history, history_unread, thread_marker, prefixes, create_channels, formatting }),
+            _ => Err(anyhow!("Expected exactly two arguments")),
+        }
+    }
+
+}
@@ -0,0 +1,35 @@
+use chrono::Utc;
+
+/// Split a CTCP message into its command, uppercased, and its argument
+pub fn parse(text: &str) -> Option<(String, &str)> {
+    let inner = text.strip_prefix('\x01')?;
+    let inner = inner.strip_suffix('\x01').unwrap_or(inner);
+    let mut parts = inner.splitn(2, ' ');
+    let command = parts.next()?.to_ascii_uppercase();
+    Some((command, parts.next().unwrap_or("")))
+}
+
+/// Answer to a CTCP query, which the bridge gives on behalf of Rocket
+/// users. Returns None for the queries we ignore.
+pub fn answer(command: &str, arg: &str) -> Option<String> {
+    let reply = match command {
+        "VERSION" => format!("VERSION croquette v{}, bridging Rocket.Chat", env!("CARGO_PKG_VERSION")),
+        "PING" => format!("PING {}", arg),
+        "TIME" => format!("TIME {}", Utc::now().to_rfc2822()),
+        _ => return None,
+    };
+    Some(format!("\x01{}\x01", reply))
+}
+
+/// Text of a Rocket "/me" message, which is the whole line in italics
+pub fn me(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix('_')?.strip_suffix('_')?;
+    if inner.trim().is_empty() || inner.contains('_') || inner.contains('\n') {
+        return None
+    }
+    Some(inner)
+}
+
+pub fn action(text: &str) -> String {
+    format!("\x01ACTION {}\x01", text)
+}
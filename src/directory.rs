@@ -0,0 +1,97 @@
+//! Channel listing (LIST) from the Rocket room directory
+use anyhow::Result;
+use rasta::Handle;
+use serde_json::{Value, json};
+
+/// Rooms fetched per directory call
+const PAGE: usize = 100;
+
+/// Most rooms listed for one LIST command
+const MAX_ROOMS: usize = 1000;
+
+/// LIST parameters: channel masks, and ELIST user count bounds (`>N`, `<N`)
+#[derive(Debug, Default)]
+pub struct Filter {
+    masks: Vec<String>,
+    more_than: Option<usize>,
+    less_than: Option<usize>,
+}
+
+impl Filter {
+
+    pub fn parse(params: Option<&str>) -> Self {
+        let mut filter = Filter::default();
+        for param in params.into_iter().flat_map(|p| p.split(',')).filter(|p| !p.is_empty()) {
+            if let Some(n) = param.strip_prefix('>').and_then(|n| n.parse().ok()) {
+                filter.more_than = Some(n);
+            } else if let Some(n) = param.strip_prefix('<').and_then(|n| n.parse().ok()) {
+                filter.less_than = Some(n);
+            } else {
+                filter.masks.push(param.to_lowercase());
+            }
+        }
+        filter
+    }
+
+    /// Text to search the directory for, when a single mask without
+    /// wildcards narrows the listing down
+    pub fn search_text(&self, prefixes: &str) -> String {
+        match &self.masks[..] {
+            [mask] if !mask.contains(|c| c == '*' || c == '?') => mask.trim_start_matches(|c| prefixes.contains(c)).to_string(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn matches(&self, channel: &str, users: usize) -> bool {
+        let channel = channel.to_lowercase();
+        self.more_than.map_or(true, |n| users > n)
+            && self.less_than.map_or(true, |n| users < n)
+            && (self.masks.is_empty() || self.masks.iter().any(|mask| wildcard(mask, &channel)))
+    }
+
+}
+
+/// Match an IRC mask, where `*` stands for any sequence and `?` for any character
+fn wildcard(mask: &str, text: &str) -> bool {
+    let (mask, text): (Vec<char>, Vec<char>) = (mask.chars().collect(), text.chars().collect());
+    // position of the last `*` in the mask, and where it started matching in the text
+    let (mut m, mut t, mut star) = (0, 0, None);
+    while t < text.len() {
+        match mask.get(m) {
+            Some('*') => { star = Some((m, t)); m += 1; },
+            Some(c) if *c == '?' || *c == text[t] => { m += 1; t += 1; },
+            _ => match star {
+                Some((sm, st)) => { m = sm + 1; t = st + 1; star = Some((sm, st + 1)); },
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+/// Rooms of the directory matching `text`, including the ones we are not in
+pub async fn search(server: &mut Handle, text: &str) -> Result<Vec<Value>> {
+    let mut rooms = vec![];
+    loop {
+        let query = json!({ "text": text, "type": "channels", "sortBy": "name", "sortDirection": "asc",
+            "offset": rooms.len(), "limit": PAGE });
+        let result = server.call("browseChannels", vec![query]).await?;
+        let page = result["results"].as_array().cloned().unwrap_or_default();
+        let total = result["total"].as_u64().unwrap_or(0) as usize;
+        let done = page.len() < PAGE;
+        rooms.extend(page);
+        if done || rooms.len() >= total.min(MAX_ROOMS) {
+            return Ok(rooms)
+        }
+    }
+}
+
+/// Member count of a directory entry
+pub fn users(room: &Value) -> usize {
+    room["usersCount"].as_u64().unwrap_or(0) as usize
+}
+
+/// Topic of a directory entry
+pub fn topic(room: &Value) -> &str {
+    room["topic"].as_str().unwrap_or("")
+}
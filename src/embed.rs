@@ -0,0 +1,94 @@
+use reqwest::Url;
+use serde_json::Value;
+
+/// Longest description we show for an attachment or link preview, in characters
+const MAX_DESCRIPTION: usize = 200;
+
+/// Longest quote of a message we show when referring to it, in characters
+const MAX_QUOTE: usize = 40;
+
+/// Hosts serving animated GIFs, as used by Rocket's GIF picker
+const GIF_HOSTS: [&str; 3] = ["giphy.com", "tenor.com", "gfycat.com"];
+
+/// First non-empty string among the given fields of a JSON object
+fn field<'a>(obj: &'a Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter()
+        .filter_map(|k| obj[*k].as_str())
+        .map(str::trim)
+        .find(|s| !s.is_empty())
+}
+
+fn is_gif(content_type: Option<&str>, url: Option<&str>) -> bool {
+    content_type == Some("image/gif")
+        || url.and_then(|url| Url::parse(url).ok()).map_or(false, |url| {
+            url.path().to_lowercase().ends_with(".gif")
+                || url.host_str().map_or(false, |host| GIF_HOSTS.iter()
+                    .any(|gif| host == *gif || host.ends_with(&format!(".{}", gif))))
+        })
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(max) {
+        Some((idx, _)) => format!("{}...", &line[..idx]),
+        None => line,
+    }
+}
+
+/// Flatten a description to a single line of bounded length
+pub fn shorten(text: &str) -> String {
+    truncate(text, MAX_DESCRIPTION)
+}
+
+/// Short single-line quote of a message, when referring to it
+pub fn quote(text: &str) -> String {
+    truncate(text, MAX_QUOTE)
+}
+
+fn format_embed(gif: bool, title: Option<&str>, description: Option<&str>, url: Option<&str>) -> Option<String> {
+    let mut out = match (title, url) {
+        (Some(title), Some(url)) => format!("[{}]({})", title, url),
+        (Some(title), None) => format!("[{}]", title),
+        (None, Some(url)) => url.to_string(),
+        (None, None) if description.is_some() => String::new(),
+        (None, None) => return None,
+    };
+
+    if let Some(description) = description {
+        if !out.is_empty() { out += " - "; }
+        out += &shorten(description);
+    }
+
+    if gif {
+        out = format!("GIF {}", out);
+    }
+
+    Some(out)
+}
+
+/// One-line description of a message attachment (uploaded file, GIF, or link preview)
+pub fn describe_attachment(attachment: &Value) -> String {
+    let title = field(attachment, &["title", "author_name"]);
+    let description = field(attachment, &["description", "text"]);
+    let url = field(attachment, &["title_link", "image_url", "video_url", "audio_url", "thumb_url"]);
+    let gif = is_gif(field(attachment, &["image_type"]), field(attachment, &["image_url"]).or(url));
+
+    format_embed(gif, title, description, url)
+        .unwrap_or_else(|| "<UNKNOWN ATTACHMENT>".to_string())
+}
+
+/// One-line description of the preview of a URL contained in a message, if the
+/// backend resolved any metadata for it. Bare URLs are already visible in the text.
+pub fn describe_url(preview: &Value) -> Option<String> {
+    let meta = &preview["meta"];
+    let url = field(preview, &["url"]);
+    let title = field(meta, &["ogTitle", "twitterTitle", "oembedTitle", "pageTitle"]);
+    let description = field(meta, &["ogDescription", "twitterDescription", "description"]);
+    let gif = is_gif(field(&preview["headers"], &["contentType"]), url);
+
+    if title.is_none() && description.is_none() {
+        return None
+    }
+
+    format_embed(gif, title, description, url)
+}
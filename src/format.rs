@@ -0,0 +1,217 @@
+//! Translation between Rocket markdown and mIRC formatting codes
+
+const BOLD: char = '\x02';
+const ITALIC: char = '\x1d';
+const STRIKE: char = '\x1e';
+const MONO: char = '\x11';
+const UNDERLINE: char = '\x1f';
+const REVERSE: char = '\x16';
+const COLOR: char = '\x03';
+const RESET: char = '\x0f';
+
+/// Whether a character can delimit a markdown span, like the start of the line
+fn boundary(c: Option<&char>) -> bool {
+    c.map_or(true, |c| !c.is_alphanumeric())
+}
+
+/// Position of the delimiter closing a span opened at `start`
+fn closing(chars: &[char], start: usize, delim: &[char]) -> Option<usize> {
+    let n = delim.len();
+    (start + n + 1 ..= chars.len().checked_sub(n)?)
+        .find(|&j| &chars[j..j+n] == delim
+            && !chars[j-1].is_whitespace()
+            && boundary(chars.get(j+n)))
+}
+
+/// Convert inline markdown of a single line: emphasis, strike, code and links
+fn inline(chars: &[char]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '`' => if let Some(end) = chars[i+1..].iter().position(|&c| c == '`') {
+                out.push(MONO);
+                out.extend(&chars[i+1..i+1+end]);
+                out.push(MONO);
+                i += end + 2;
+                continue
+            },
+            '[' => {
+                let text_end = chars[i..].iter().position(|&c| c == ']').map(|p| i + p);
+                if let Some(text_end) = text_end.filter(|&e| chars.get(e + 1) == Some(&'(')) {
+                    if let Some(url_end) = chars[text_end..].iter().position(|&c| c == ')').map(|p| text_end + p) {
+                        let text: String = chars[i+1..text_end].iter().collect();
+                        let url: String = chars[text_end+2..url_end].iter().collect();
+                        if text == url || text.is_empty() {
+                            out += &url;
+                        } else {
+                            out += &format!("{} <{}>", text, url);
+                        }
+                        i = url_end + 1;
+                        continue
+                    }
+                }
+            },
+            '*' | '_' | '~' if boundary(if i == 0 { None } else { chars.get(i-1) }) => {
+                let n = if chars.get(i+1) == Some(&c) { 2 } else { 1 };
+                let delim = &chars[i..i+n];
+                let opens = chars.get(i+n).map_or(false, |c| !c.is_whitespace());
+                if let (true, Some(end)) = (opens, closing(chars, i, delim)) {
+                    let code = match c { '*' => BOLD, '_' => ITALIC, _ => STRIKE };
+                    out.push(code);
+                    out += &inline(&chars[i+n..end]);
+                    out.push(code);
+                    i = end + n;
+                    continue
+                }
+            },
+            _ => {},
+        }
+        out.push(c);
+        i += 1;
+    }
+
+    out
+}
+
+/// Convert Rocket markdown to IRC formatting codes. Code blocks are
+/// shown in monospace, without their fences.
+pub fn to_irc(text: &str) -> String {
+    let mut lines = vec![];
+    let mut fenced = false;
+
+    for line in text.split('\n') {
+        if let Some(rest) = line.trim_start().strip_prefix("```") {
+            // a whole block on one line
+            if let Some(code) = rest.strip_suffix("```") {
+                lines.push(format!("{}{}{}", MONO, code, MONO));
+            } else {
+                fenced = !fenced;
+            }
+            continue
+        }
+
+        if fenced {
+            lines.push(if line.is_empty() { String::new() } else { format!("{}{}{}", MONO, line, MONO) });
+        } else {
+            lines.push(inline(&line.chars().collect::<Vec<_>>()));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Convert IRC formatting codes to Rocket markdown. Colours, underline
+/// and reverse have no equivalent and are dropped.
+pub fn to_markdown(text: &str) -> String {
+    let mut out = String::new();
+    let mut open: Vec<char> = vec![];
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let delim = match c {
+            BOLD => '*',
+            ITALIC => '_',
+            STRIKE => '~',
+            MONO => '`',
+            COLOR => {
+                // \x03 is followed by up to two digits, then optionally
+                // a comma and up to two digits for the background
+                let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let found = chars.next_if(char::is_ascii_digit).is_some();
+                    if found {
+                        chars.next_if(char::is_ascii_digit);
+                    }
+                    found
+                };
+                if digits(&mut chars) {
+                    let mut lookahead = chars.clone();
+                    if lookahead.next() == Some(',') && lookahead.peek().map_or(false, char::is_ascii_digit) {
+                        chars.next();
+                        digits(&mut chars);
+                    }
+                }
+                continue
+            },
+            RESET => {
+                out.extend(open.drain(..).rev());
+                continue
+            },
+            UNDERLINE | REVERSE => continue,
+            c => {
+                out.push(c);
+                continue
+            },
+        };
+
+        if open.contains(&delim) {
+            open.retain(|&d| d != delim);
+        } else {
+            open.push(delim);
+        }
+        out.push(delim);
+    }
+
+    out.extend(open.drain(..).rev());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_to_irc() {
+        assert_eq!(to_irc("*bold* and _italic_"), "\x02bold\x02 and \x1ditalic\x1d");
+        assert_eq!(to_irc("**bold** ~~gone~~"), "\x02bold\x02 \x1egone\x1e");
+        assert_eq!(to_irc("run `cargo *build*`"), "run \x11cargo *build*\x11");
+        assert_eq!(to_irc("*nested _italic_*"), "\x02nested \x1ditalic\x1d\x02");
+    }
+
+    #[test]
+    fn markdown_left_alone() {
+        assert_eq!(to_irc("snake_case_name"), "snake_case_name");
+        assert_eq!(to_irc("2 * 3 * 4"), "2 * 3 * 4");
+        assert_eq!(to_irc("*unclosed"), "*unclosed");
+        assert_eq!(to_irc("`unclosed"), "`unclosed");
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(to_irc("[docs](https://example.com)"), "docs <https://example.com>");
+        assert_eq!(to_irc("[https://example.com](https://example.com)"), "https://example.com");
+        assert_eq!(to_irc("[not a link]"), "[not a link]");
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(to_irc("```\nlet x = *y*;\n\nx\n```"), "\x11let x = *y*;\x11\n\n\x11x\x11");
+        assert_eq!(to_irc("```inline```"), "\x11inline\x11");
+    }
+
+    #[test]
+    fn irc_to_markdown() {
+        assert_eq!(to_markdown("\x02bold\x02 and \x1ditalic\x1d"), "*bold* and _italic_");
+        assert_eq!(to_markdown("\x1egone\x1e \x11code\x11"), "~gone~ `code`");
+        // unterminated spans are closed at the end, or on reset
+        assert_eq!(to_markdown("\x02bold"), "*bold*");
+        assert_eq!(to_markdown("\x02\x1dboth\x0f plain"), "*_both_* plain");
+    }
+
+    #[test]
+    fn dropped_codes() {
+        assert_eq!(to_markdown("\x0304red\x03 \x0312,01blue\x03"), "red blue");
+        assert_eq!(to_markdown("\x034,5x"), "x");
+        assert_eq!(to_markdown("\x03,5x"), ",5x");
+        assert_eq!(to_markdown("\x1funder\x1f \x16rev\x16"), "under rev");
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in ["*bold* _italic_ ~strike~", "`code` and *more*", "plain text"].iter() {
+            assert_eq!(to_markdown(&to_irc(text)), *text);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Group direct messages, shown as channels named after their members
+
+/// Separates usernames in the channel name of a group conversation. Unlike
+/// commas, it is valid in channel names, and never appears in Rocket usernames.
+const SEPARATOR: char = '+';
+
+/// Channel of a group conversation between these users, leaving us out
+pub fn channel_name(users: &[String], me: &str) -> String {
+    let mut others: Vec<&str> = users.iter().map(String::as_str).filter(|user| *user != me).collect();
+    others.sort();
+    others.dedup();
+    format!("&{}", others.join(&SEPARATOR.to_string()))
+}
+
+/// Users of a group conversation channel, other than us. Returns None if the
+/// channel does not name at least two other users.
+pub fn usernames(channel: &str) -> Option<Vec<String>> {
+    let names = channel.strip_prefix('&')?;
+    let users: Vec<String> = names.split(SEPARATOR)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if users.len() < 2 {
+        return None
+    }
+    Some(users)
+}
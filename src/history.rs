@@ -0,0 +1,177 @@
+use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}};
+use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use irc_proto::{Command, Message, Prefix, message::Tag};
+use rasta::Handle;
+use serde_json::{Value, json};
+use crate::{caps::{Caps, message_tags}, ctcp, embed, format, multiline};
+
+static NEXT_BATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of messages returned by one CHATHISTORY request
+pub const MAX_LIMIT: usize = 100;
+
+/// Timestamp of a Rocket message, in milliseconds
+fn timestamp(message: &Value) -> i64 {
+    message["ts"]["$date"].as_i64().unwrap_or(0)
+}
+
+/// Messages of a history call, oldest first
+fn sorted(result: Value) -> Vec<Value> {
+    let mut messages = result["messages"].as_array().cloned().unwrap_or_default();
+    messages.sort_by_key(timestamp);
+    messages
+}
+
+/// Fetch up to `limit` messages of a room sent before `before` (a timestamp in
+/// milliseconds), or the most recent ones. Messages are returned oldest first.
+pub async fn load(server: &mut Handle, rid: &str, before: Option<i64>, limit: usize) -> Result<Vec<Value>> {
+    let end = before.map_or(Value::Null, |ts| json!({ "$date": ts }));
+    let result = server.call("loadHistory", vec![json!(rid), end, json!(limit), Value::Null]).await?;
+    Ok(sorted(result))
+}
+
+/// Fetch up to `limit` messages of a room sent after `after`, oldest first
+pub async fn load_after(server: &mut Handle, rid: &str, after: i64, limit: usize) -> Result<Vec<Value>> {
+    let result = server.call("loadNextMessages", vec![json!(rid), json!({ "$date": after }), json!(limit)]).await?;
+    Ok(sorted(result))
+}
+
+/// Fetch the messages replayed when joining a room: the latest ones, up to
+/// `limit`, only keeping those after `since` if given
+pub async fn recent(server: &mut Handle, rid: &str, since: Option<i64>, limit: usize) -> Result<Vec<Value>> {
+    let messages = load(server, rid, None, limit).await?;
+    Ok(match since {
+        Some(since) => messages.into_iter().filter(|m| timestamp(m) > since).collect(),
+        None => messages,
+    })
+}
+
+/// When the user last read each room, by room ID, from their subscriptions
+pub async fn last_read(server: &mut Handle) -> Result<HashMap<String, i64>> {
+    let subscriptions = server.call("subscriptions/get", vec![]).await?;
+    Ok(subscriptions.as_array().into_iter().flatten()
+        .filter_map(|sub| Some((sub["rid"].as_str()?.to_string(), sub["ls"]["$date"].as_i64()?)))
+        .collect())
+}
+
+/// A CHATHISTORY request, with its references resolved to timestamps
+pub enum Query {
+    Latest(Option<i64>),
+    Before(i64),
+    After(i64),
+    Around(i64),
+    Between(i64, i64),
+}
+
+/// Resolve a CHATHISTORY reference (`timestamp=...` or `msgid=...`) to a
+/// timestamp in milliseconds. Returns None if it is invalid or unknown.
+pub async fn resolve(server: &mut Handle, reference: &str) -> Option<i64> {
+    let mut parts = reference.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some("timestamp"), Some(time)) => DateTime::parse_from_rfc3339(time).ok().map(|t| t.timestamp_millis()),
+        (Some("msgid"), Some(id)) => {
+            let message = server.call("getSingleMessage", vec![json!(id)]).await.ok()?;
+            message["ts"]["$date"].as_i64()
+        },
+        _ => None,
+    }
+}
+
+/// Fetch the messages answering a CHATHISTORY request, oldest first
+pub async fn query(server: &mut Handle, rid: &str, query: Query, limit: usize) -> Result<Vec<Value>> {
+    Ok(match query {
+        Query::Latest(None) => load(server, rid, None, limit).await?,
+        Query::Latest(Some(after)) => load(server, rid, None, limit).await?
+            .into_iter().filter(|m| timestamp(m) > after).collect(),
+        Query::Before(before) => load(server, rid, Some(before), limit).await?,
+        Query::After(after) => load_after(server, rid, after, limit).await?,
+        Query::Around(ts) => {
+            // the message at the reference itself counts as before
+            let mut messages = load(server, rid, Some(ts + 1), (limit + 1) / 2).await?;
+            messages.extend(load_after(server, rid, ts, limit / 2).await?);
+            messages
+        },
+        Query::Between(start, end) if start <= end => load_after(server, rid, start, limit).await?
+            .into_iter().filter(|m| timestamp(m) < end).collect(),
+        Query::Between(start, end) => load(server, rid, Some(start), limit).await?
+            .into_iter().filter(|m| timestamp(m) > end).collect(),
+    })
+}
+
+/// The IRC lines a Rocket message shows as: its text, then a description of
+/// each attachment. System messages (joins, topic changes...) are left out.
+fn lines(message: &Value, formatting: bool) -> Vec<String> {
+    if message["t"].is_string() {
+        return vec![]
+    }
+
+    let mut lines = vec![];
+    if let Some(text) = message["msg"].as_str().filter(|text| !text.trim().is_empty()) {
+        let (me, text) = match ctcp::me(text) {
+            Some(me) => (true, me),
+            None => (false, text),
+        };
+        let text = if formatting { format::to_irc(text) } else { text.to_string() };
+        lines.push(if me { ctcp::action(&text) } else { text });
+    }
+    for file in message["attachments"].as_array().into_iter().flatten() {
+        lines.push(format!("\x01ACTION {}\x01", embed::describe_attachment(file)));
+    }
+    lines
+}
+
+/// Put the date of a message in front of its text, for clients without server-time
+fn stamp(message: &Value, line: String) -> String {
+    let date = match message["ts"]["$date"].as_i64() {
+        Some(millis) => Utc.timestamp_millis(millis).format("%Y-%m-%d %H:%M").to_string(),
+        None => return line,
+    };
+    match line.strip_prefix("\x01ACTION ") {
+        Some(action) => format!("\x01ACTION [{}] {}", date, action),
+        None => format!("[{}] {}", date, line),
+    }
+}
+
+/// Format past messages of a channel or private conversation for a client: wrapped in
+/// a `chathistory` batch if it supports batches and server-time, or dated in the text
+/// otherwise. `nick` is our own nick, the target of private messages sent to us.
+pub fn replay(messages: &[Value], target: &str, nick: &str, server: &str, caps: &Caps, formatting: bool) -> Vec<Message> {
+    let batch = caps.has("batch") && caps.has("server-time");
+    let reference = format!("history{}", NEXT_BATCH.fetch_add(1, Ordering::Relaxed));
+
+    let mut output = vec![];
+    for message in messages {
+        let user = match message["u"]["username"].as_str() {
+            Some(user) => user,
+            None => continue,
+        };
+        let prefix = Prefix::Nickname(user.into(), user.into(), server.into());
+        // in direct messages, the other side writes to us
+        let to = if user == target { nick } else { target };
+
+        let lines = lines(message, formatting).into_iter()
+            .flat_map(|line| multiline::split(&line))
+            .map(|(line, _)| line);
+        for (i, line) in lines.enumerate() {
+            let mut tags = message_tags(message, i == 0).unwrap_or_default();
+            let text = if batch {
+                tags.push(Tag("batch".into(), Some(reference.clone())));
+                line
+            } else {
+                stamp(message, line)
+            };
+            output.push(Message { tags: Some(tags), prefix: Some(prefix.clone()),
+                command: Command::PRIVMSG(to.into(), text) });
+        }
+    }
+
+    if batch && !output.is_empty() {
+        let batch = |args| Message { tags: None, prefix: Some(Prefix::ServerName(server.into())),
+            command: Command::Raw("BATCH".into(), args) };
+        output.insert(0, batch(vec![format!("+{}", reference), "chathistory".into(), target.into()]));
+        output.push(batch(vec![format!("-{}", reference)]));
+    }
+
+    output
+}
@@ -1,6 +1,24 @@
 use anyhow::Result;
 
+mod art;
+mod auth;
+mod bouncer;
+mod caps;
+mod codec;
+mod config;
+mod ctcp;
+mod directory;
+mod embed;
+mod format;
+mod groups;
+mod history;
+mod members;
+mod multiline;
+mod naming;
 mod proxy;
+mod reactions;
+mod tls;
+mod users;
 mod util;
 
 #[tokio::main]
@@ -9,20 +27,37 @@ pub async fn main() -> Result<()> {
     env_logger::init();
 
     let mut args = std::env::args();
-
-    if args.len() != 3 {
-        eprintln!("Usage: croquette <IRC bind address> <rocket server>");
-        eprintln!("   Example: croquette 0.0.0.0:6668 rocket.example.com");
-        eprintln!("   export RUST_LOG=[error|warn|info|debug|trace] for logging");
-        return Ok(());
-    }
-
     args.next();
-    let bind = args.next().unwrap();
-    let backend = args.next().unwrap();
-
-    //let bind, backend;
 
-    let proxy = proxy::ProxyListener::new(bind, backend);
+    let config = match config::Config::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: croquette [options] <IRC bind address> <rocket server>");
+            eprintln!("   Example: croquette 0.0.0.0:6668 rocket.example.com");
+            eprintln!("   Options:");
+            eprintln!("     --tls-cert <file>   PEM certificate chain for TLS on the IRC side");
+            eprintln!("     --tls-key <file>    PEM private key for TLS (reloaded on SIGHUP)");
+            eprintln!("     --images            Render image attachments as colour art");
+            eprintln!("     --image-width <n>   Maximum width of rendered images, in characters (default 40)");
+            eprintln!("     --image-height <n>  Maximum height of rendered images, in lines (default 20)");
+            eprintln!("     --image-line-bytes <n>  Maximum size of a rendered line, in bytes (default 400)");
+            eprintln!("     --bouncer           Keep Rocket sessions alive between IRC connections");
+            eprintln!("     --backlog <n>       Messages kept for detached bouncer sessions (default 2000)");
+            eprintln!("     --history <n>       Past messages replayed when joining a channel (default 0)");
+            eprintln!("     --history-unread    Only replay messages not read yet (up to --history, or 100)");
+            eprintln!("     --thread-marker <s> Marks thread references like >>abcd in messages, empty to disable (default >>)");
+            eprintln!("     --prefix-public <c> Channel prefix of public rooms (default #)");
+            eprintln!("     --prefix-private <c>  Channel prefix of private rooms (default &)");
+            eprintln!("     --prefix-discussion <c>  Channel prefix of discussions (default !)");
+            eprintln!("     --prefix-team <c>   Channel prefix of teams (default +)");
+            eprintln!("     --create-channels   Create Rocket rooms when joining channels that do not exist");
+            eprintln!("     --no-formatting     Pass markdown and IRC formatting through unchanged by default");
+            eprintln!("   export RUST_LOG=[error|warn|info|debug|trace] for logging");
+            return Ok(());
+        }
+    };
+
+    let proxy = proxy::ProxyListener::new(config)?;
     proxy.run().await
 }
@@ -0,0 +1,70 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Known members of a channel, along with the NAMES mode character
+/// ('=' for public, '*' for private) and the current topic
+#[derive(Debug)]
+pub struct Channel {
+    pub modechar: char,
+    pub topic: Option<String>,
+    pub users: BTreeSet<String>,
+}
+
+/// Membership of the channels the client is in, indexed by IRC channel name.
+/// Kept up to date from Rocket system messages, so that NAMES can be answered
+/// at any time without a round-trip to the backend.
+#[derive(Debug, Default)]
+pub struct Members {
+    channels: HashMap<String, Channel>,
+}
+
+impl Members {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the userlist of a channel, keeping its topic if we already knew it
+    pub fn set(&mut self, channel: String, modechar: char, users: impl IntoIterator<Item=String>) {
+        let topic = self.channels.remove(&channel).and_then(|chan| chan.topic);
+        self.channels.insert(channel, Channel { modechar, topic, users: users.into_iter().collect() });
+    }
+
+    pub fn set_topic(&mut self, channel: &str, topic: Option<String>) {
+        if let Some(chan) = self.channels.get_mut(channel) {
+            chan.topic = topic;
+        }
+    }
+
+    pub fn get(&self, channel: &str) -> Option<&Channel> {
+        self.channels.get(channel)
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item=(&String, &Channel)> {
+        self.channels.iter()
+    }
+
+    /// Record a user joining. Returns false if the user was already known
+    /// to be present, or if we are not tracking this channel.
+    pub fn add(&mut self, channel: &str, user: &str) -> bool {
+        match self.channels.get_mut(channel) {
+            Some(chan) => chan.users.insert(user.to_string()),
+            None => false,
+        }
+    }
+
+    /// Record a user leaving. Returns false if the user was not known
+    /// to be present.
+    pub fn remove(&mut self, channel: &str, user: &str) -> bool {
+        match self.channels.get_mut(channel) {
+            Some(chan) => chan.users.remove(user),
+            None => false,
+        }
+    }
+
+    /// Stop tracking a channel, when we leave it. Returns false if
+    /// we were not tracking it.
+    pub fn forget(&mut self, channel: &str) -> bool {
+        self.channels.remove(channel).is_some()
+    }
+
+}
@@ -0,0 +1,125 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use irc_proto::{Command, Message, Prefix, message::Tag};
+use crate::{caps::Caps, ctcp};
+
+/// Longest text we put in a single PRIVMSG, in bytes, leaving room
+/// for the prefix and target within the 512 bytes of an IRC line
+pub const MAX_BYTES: usize = 400;
+
+/// Largest multiline batch we accept from a client, in bytes of text
+pub const MAX_BATCH_BYTES: usize = 4096;
+
+/// Most lines we accept in a multiline batch from a client
+pub const MAX_BATCH_LINES: usize = 100;
+
+/// Value of the draft/multiline capability, for CAP LS 302
+pub fn limits() -> String {
+    format!("max-bytes={},max-lines={}", MAX_BATCH_BYTES, MAX_BATCH_LINES)
+}
+
+static NEXT_BATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Split a text into lines that fit in a PRIVMSG, cutting long lines at
+/// whitespace or at least at a character boundary. Parts of a cut line
+/// are returned with true, as they continue the previous part.
+/// ACTIONs are split inside their CTCP framing, each part being an ACTION.
+pub fn split(text: &str) -> Vec<(String, bool)> {
+    match text.strip_prefix("\x01ACTION ").and_then(|inner| inner.strip_suffix('\x01')) {
+        Some(inner) => split_at(inner, MAX_BYTES - ctcp::action("").len()).into_iter()
+            .map(|(line, concat)| (ctcp::action(&line), concat))
+            .collect(),
+        None => split_at(text, MAX_BYTES),
+    }
+}
+
+fn split_at(text: &str, max: usize) -> Vec<(String, bool)> {
+    let mut output = vec![];
+
+    for line in text.split('\n').map(|line| line.trim_end_matches('\r')) {
+        let (mut rest, mut concat) = (line, false);
+        while rest.len() > max {
+            let mut cut = max;
+            while !rest.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            if let Some(space) = rest[..cut].rfind(' ').filter(|&space| space > 0) {
+                cut = space + 1;
+            }
+            output.push((rest[..cut].to_string(), concat));
+            rest = &rest[cut..];
+            concat = true;
+        }
+        if !rest.is_empty() {
+            output.push((rest.to_string(), concat));
+        }
+    }
+
+    output
+}
+
+/// PRIVMSGs carrying a text for a client: a draft/multiline batch if the text
+/// needs several lines and the client supports it, or separate messages otherwise.
+/// The message ID, if any, is only sent once.
+pub fn messages(tags: &[Tag], prefix: &Option<Prefix>, target: &str, text: &str, caps: &Caps) -> Vec<Message> {
+    let lines = split(text);
+    let privmsg = |tags: Vec<Tag>, line: String| Message { tags: Some(tags), prefix: prefix.clone(),
+        command: Command::PRIVMSG(target.into(), line) };
+
+    if lines.len() > 1 && caps.has("batch") && caps.has("draft/multiline") {
+        let reference = format!("multiline{}", NEXT_BATCH.fetch_add(1, Ordering::Relaxed));
+        let batch = |tags, args| Message { tags, prefix: prefix.clone(), command: Command::Raw("BATCH".into(), args) };
+
+        let mut output = vec![batch(Some(tags.to_vec()), vec![format!("+{}", reference), "draft/multiline".into(), target.into()])];
+        for (line, concat) in lines {
+            let mut tags = vec![Tag("batch".into(), Some(reference.clone()))];
+            if concat {
+                tags.push(Tag("draft/multiline-concat".into(), None));
+            }
+            output.push(privmsg(tags, line));
+        }
+        output.push(batch(None, vec![format!("-{}", reference)]));
+        output
+    } else {
+        lines.into_iter().enumerate()
+            .map(|(i, (line, _))| {
+                let tags = tags.iter().filter(|Tag(key, _)| i == 0 || key != "msgid").cloned().collect();
+                privmsg(tags, line)
+            })
+            .collect()
+    }
+}
+
+/// A draft/multiline batch being received from a client
+pub struct Incoming {
+    pub reference: String,
+    pub target: String,
+    /// Tags of the batch, which apply to the whole message
+    pub tags: Option<Vec<Tag>>,
+    pub text: String,
+    lines: usize,
+}
+
+impl Incoming {
+
+    pub fn new(reference: String, target: String, tags: Option<Vec<Tag>>) -> Self {
+        Incoming { reference, target, tags, text: String::new(), lines: 0 }
+    }
+
+    /// Add a line to the batch. Fails with the FAIL code and the limit
+    /// if the batch gets over the limits we advertised.
+    pub fn push(&mut self, line: &str, concat: bool) -> Result<(), (&'static str, usize)> {
+        if self.lines >= MAX_BATCH_LINES {
+            return Err(("MULTILINE_MAX_LINES", MAX_BATCH_LINES))
+        }
+        if self.text.len() + line.len() + 1 > MAX_BATCH_BYTES {
+            return Err(("MULTILINE_MAX_BYTES", MAX_BATCH_BYTES))
+        }
+        if !concat && self.lines > 0 {
+            self.text.push('\n');
+        }
+        self.text += line;
+        self.lines += 1;
+        Ok(())
+    }
+
+}
@@ -0,0 +1,291 @@
+//! Mapping between Rocket rooms and IRC channel names
+use std::collections::HashMap;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Public,
+    Private,
+    Discussion,
+    Team,
+}
+
+/// Channel prefix for each kind of room
+#[derive(Debug, Clone)]
+pub struct Prefixes {
+    pub public: char,
+    pub private: char,
+    pub discussion: char,
+    pub team: char,
+}
+
+impl Default for Prefixes {
+    fn default() -> Self {
+        Prefixes { public: '#', private: '&', discussion: '!', team: '+' }
+    }
+}
+
+impl Prefixes {
+
+    fn get(&self, kind: Kind) -> char {
+        match kind {
+            Kind::Public => self.public,
+            Kind::Private => self.private,
+            Kind::Discussion => self.discussion,
+            Kind::Team => self.team,
+        }
+    }
+
+    /// Kind of room a prefix stands for. When several kinds share a
+    /// prefix, the first one is returned.
+    fn kind(&self, prefix: char) -> Option<Kind> {
+        [Kind::Public, Kind::Private, Kind::Discussion, Kind::Team].iter()
+            .copied()
+            .find(|kind| self.get(*kind) == prefix)
+    }
+
+}
+
+/// Kind and name of a room, from its Rocket record. Discussions are
+/// named by their title, as their name is a generated identifier.
+pub fn describe(room: &Value) -> Option<(Kind, &str)> {
+    let kind = if room["prid"].is_string() {
+        Kind::Discussion
+    } else if room["teamMain"].as_bool() == Some(true) {
+        Kind::Team
+    } else {
+        match room["t"].as_str()? {
+            "c" => Kind::Public,
+            "p" => Kind::Private,
+            _ => return None,
+        }
+    };
+    let name = match kind {
+        Kind::Discussion => room["fname"].as_str().or_else(|| room["name"].as_str())?,
+        _ => room["name"].as_str()?,
+    };
+    Some((kind, name))
+}
+
+/// Percent-encode the characters not allowed in channel names:
+/// whitespace, control characters, commas, colons and non-ASCII
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if c.is_ascii_graphic() && !",:%".contains(c) {
+            escaped.push(c);
+        } else {
+            for byte in c.to_string().bytes() {
+                escaped += &format!("%{:02X}", byte);
+            }
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut input = name.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Channel names given to rooms. Names are assigned the first time a room is
+/// seen and never change afterwards. When rooms would get the same name (IRC
+/// names are case-insensitive, and discussion titles are not unique), the
+/// start of their ID is appended: to all of them for the rooms known at login,
+/// so names don't depend on the order the server lists rooms in, and to the
+/// newcomer for rooms seen later, as renaming a channel would confuse clients.
+#[derive(Debug, Default)]
+pub struct Naming {
+    prefixes: Prefixes,
+    /// Room IDs by lowercased channel name
+    rooms: HashMap<String, String>,
+    /// Channel names by room ID
+    channels: HashMap<String, String>,
+}
+
+/// Length of the room ID suffix telling apart rooms with the same name
+const SUFFIX: usize = 4;
+
+impl Naming {
+
+    pub fn new(prefixes: Prefixes) -> Self {
+        Naming { prefixes, ..Default::default() }
+    }
+
+    /// Channel name of a room, without telling it apart from other rooms
+    pub fn base(&self, kind: Kind, name: &str) -> String {
+        format!("{}{}", self.prefixes.get(kind), escape(name))
+    }
+
+    fn insert(&mut self, rid: &str, channel: String) -> String {
+        self.rooms.insert(channel.to_lowercase(), rid.to_string());
+        self.channels.insert(rid.to_string(), channel.clone());
+        channel
+    }
+
+    /// Channel of a room, assigned now if the room was not seen before
+    pub fn assign(&mut self, rid: &str, kind: Kind, name: &str) -> String {
+        if let Some(channel) = self.channels.get(rid) {
+            return channel.clone()
+        }
+
+        let mut channel = self.base(kind, name);
+        if self.rooms.contains_key(&channel.to_lowercase()) {
+            channel = format!("{}~{}", channel, &rid[..rid.len().min(SUFFIX)]);
+        }
+        if self.rooms.contains_key(&channel.to_lowercase()) {
+            channel = format!("{}~{}", self.base(kind, name), rid);
+        }
+        self.insert(rid, channel)
+    }
+
+    /// Assign channels to the rooms known at login, as `(rid, kind, name)`.
+    /// All the rooms sharing a name get their ID appended.
+    pub fn assign_all(&mut self, rooms: Vec<(String, Kind, String)>) {
+        let mut by_name: HashMap<String, Vec<(&str, String)>> = HashMap::new();
+        for (rid, kind, name) in &rooms {
+            if !self.channels.contains_key(rid) {
+                let base = self.base(*kind, name);
+                by_name.entry(base.to_lowercase()).or_default().push((rid, base));
+            }
+        }
+
+        let mut names: Vec<(String, String)> = vec![];
+        for (lower, same) in by_name {
+            if same.len() == 1 && !self.rooms.contains_key(&lower) {
+                names.extend(same.into_iter().map(|(rid, base)| (rid.to_string(), base)));
+                continue
+            }
+            let short = |rid: &str| rid[..rid.len().min(SUFFIX)].to_lowercase();
+            for &(rid, ref base) in &same {
+                let ambiguous = same.iter().filter(|(other, _)| short(other) == short(rid)).count() > 1;
+                let suffix = if ambiguous { rid } else { &rid[..rid.len().min(SUFFIX)] };
+                names.push((rid.to_string(), format!("{}~{}", base, suffix)));
+            }
+        }
+        for (rid, channel) in names {
+            self.insert(&rid, channel);
+        }
+    }
+
+    pub fn channel(&self, rid: &str) -> Option<&str> {
+        self.channels.get(rid).map(String::as_str)
+    }
+
+    pub fn rid(&self, channel: &str) -> Option<&str> {
+        self.rooms.get(&channel.to_lowercase()).map(String::as_str)
+    }
+
+    /// Kind and Rocket name of a channel that was not assigned yet, to look it up
+    pub fn parse(&self, channel: &str) -> Option<(Kind, String)> {
+        let prefix = channel.chars().next()?;
+        let kind = self.prefixes.kind(prefix)?;
+        let name = unescape(&channel[prefix.len_utf8()..])?;
+        if name.is_empty() {
+            return None
+        }
+        Some((kind, name))
+    }
+
+    pub fn is_channel(&self, target: &str) -> bool {
+        target.chars().next().map_or(false, |prefix| self.chantypes().contains(prefix))
+    }
+
+    /// Channel prefixes in use, for CHANTYPES. `&` is also used by group conversations.
+    pub fn chantypes(&self) -> String {
+        let mut types = String::new();
+        let p = &self.prefixes;
+        for prefix in [p.public, p.private, p.discussion, p.team, '&'].iter() {
+            if !types.contains(*prefix) {
+                types.push(*prefix);
+            }
+        }
+        types
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trip() {
+        for name in ["general", "café", "日本語", "50%", "a,b", "a:b", "two words", "tab\there", "%41"].iter() {
+            let escaped = escape(name);
+            assert!(escaped.chars().all(|c| c.is_ascii_graphic() && c != ',' && c != ':'), "{}", escaped);
+            assert_eq!(unescape(&escaped).as_deref(), Some(*name));
+        }
+        assert_eq!(escape("50%"), "50%25");
+        assert_eq!(escape("a,b:c"), "a%2Cb%3Ac");
+        assert_eq!(escape("café"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn unescape_invalid() {
+        assert_eq!(unescape("50%"), None);
+        assert_eq!(unescape("%4"), None);
+        assert_eq!(unescape("%zz"), None);
+        assert_eq!(unescape("%C3"), None);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let mut naming = Naming::default();
+        for (kind, name) in [(Kind::Public, "café"), (Kind::Private, "a,b"), (Kind::Discussion, "What: now?"), (Kind::Team, "100%")].iter() {
+            let channel = naming.assign(&format!("rid-{}", name), *kind, name);
+            assert_eq!(naming.parse(&channel), Some((*kind, name.to_string())));
+        }
+        assert_eq!(naming.parse("#"), None);
+        assert_eq!(naming.parse("general"), None);
+    }
+
+    #[test]
+    fn assign_is_stable() {
+        let mut naming = Naming::default();
+        let channel = naming.assign("abcdef", Kind::Public, "general");
+        assert_eq!(channel, "#general");
+        assert_eq!(naming.assign("abcdef", Kind::Private, "renamed"), "#general");
+        assert_eq!(naming.rid("#GENERAL"), Some("abcdef"));
+        assert_eq!(naming.channel("abcdef"), Some("#general"));
+    }
+
+    #[test]
+    fn collisions() {
+        let mut naming = Naming::default();
+        assert_eq!(naming.assign("abcdef", Kind::Public, "general"), "#general");
+        assert_eq!(naming.assign("ghijkl", Kind::Public, "General"), "#General~ghij");
+        assert_eq!(naming.assign("ghijmn", Kind::Public, "GENERAL"), "#GENERAL~ghijmn");
+        assert_eq!(naming.rid("#general~GHIJ"), Some("ghijkl"));
+    }
+
+    #[test]
+    fn collisions_at_login_ignore_order() {
+        let rooms = vec![
+            ("abcdef".to_string(), Kind::Discussion, "Lunch".to_string()),
+            ("ghijkl".to_string(), Kind::Discussion, "lunch".to_string()),
+            ("ghijmn".to_string(), Kind::Discussion, "LUNCH".to_string()),
+            ("opqrst".to_string(), Kind::Public, "lunch".to_string()),
+        ];
+        let mut forward = Naming::default();
+        forward.assign_all(rooms.clone());
+        let mut backward = Naming::default();
+        backward.assign_all(rooms.into_iter().rev().collect());
+
+        for naming in [&forward, &backward].iter() {
+            assert_eq!(naming.channel("abcdef"), Some("!Lunch~abcd"));
+            assert_eq!(naming.channel("ghijkl"), Some("!lunch~ghijkl"));
+            assert_eq!(naming.channel("ghijmn"), Some("!LUNCH~ghijmn"));
+            assert_eq!(naming.channel("opqrst"), Some("#lunch"));
+        }
+    }
+}
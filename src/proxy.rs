@@ -1,28 +1,29 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
 use anyhow::{Result, anyhow};
-use tokio::net::{
-    TcpListener,TcpStream,
-};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpListener};
 use tokio_util::codec::{Decoder, Framed};
-use irc_proto::{Command, IrcCodec, Message, Prefix, Response};
-use futures::{SinkExt, StreamExt, select, stream::SplitSink};
-use rasta::{Credentials, Handle, Rasta, ServerMessage, schema::{MessageID, Room, RoomEvent, RoomEventData, RoomExtraInfo, ShortUser, UserID}, session::Session};
-use crate::util::{Cache, lazy_zip};
+use irc_proto::{CapSubCommand, Command, Message, Prefix, Response, message::Tag};
+use futures::{SinkExt, StreamExt, select, channel::mpsc::{self, UnboundedReceiver, UnboundedSender}};
+use rasta::{Handle, Rasta, ServerMessage, schema::{MessageID, Room, RoomEvent, RoomEventData, RoomExtraInfo, RoomID, UserID}, session::Session};
+use serde_json::{Value, json};
+use chrono::Utc;
+use crate::{art::Renderer, auth::{Login, Refusal, Sasl, SaslStep}, bouncer::{Backlog, SessionHandle, Sessions}, caps::{self, Caps, message_tags}, codec::Codec, config::Config, ctcp, directory, embed, format, groups, history, members::{Channel, Members}, multiline::{self, Incoming}, naming::{self, Kind, Naming}, reactions::{self, Reactions}, tls::TlsConfig, users::{self, Status, User}, util::{Cache, Recent, lazy_zip}};
 use log::{debug,info,warn,error};
 
 
-#[derive(Debug)]
 pub struct ProxyListener {
-    bind: String,
-    backend: String,
+    config: Arc<Config>,
+    tls: Option<Arc<TlsConfig>>,
+    sessions: Arc<Sessions>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ClientInfo {
     nick: String,
     user: String,
-    pass: String,
+    login: Login,
     host: String,
+    caps: Caps,
 }
 
 impl Into<Prefix> for &ClientInfo {
@@ -67,46 +68,104 @@ fn server_response(server_name: &str, user: String, code: Response, mut args: Ve
     }
 }
 
-fn build_userlist(user: &str, server: &str, room: &Room, users: &[ShortUser]) -> Vec<Message> {
+/// Kind, name and NAMES mode character of a room, if it is a channel
+fn room_channel(room: &Room) -> Option<(Kind, &str, char)> {
+    match room {
+        Room::Chat { name, .. } => Some((Kind::Public, name, '=')),
+        Room::Private { name, ..} => Some((Kind::Private, name, '*')),
+        _ => None,
+    }
+}
 
-    let (room_name, modechar) = match room {
-        Room::Chat { name, .. } => (name, '='),
-        Room::Private { name, ..} => (name, '*'),
-        _ => return vec![],
-    };
+/// Numeric and reason for a failure to join a room, from the Rocket error code.
+/// Errors without a known code are reported as they are.
+fn join_error(error: &str) -> (Response, String) {
+    if error.contains("error-code-invalid") || error.contains("error-code-required") {
+        (Response::ERR_BADCHANNELKEY, "Cannot join channel (+k)".into())
+    } else if error.contains("error-not-allowed") {
+        (Response::ERR_INVITEONLYCHAN, "Cannot join channel (+i)".into())
+    } else if error.contains("error-room-not-found") || error.contains("error-invalid-room") {
+        (Response::ERR_NOSUCHCHANNEL, "No such channel".into())
+    } else {
+        (Response::ERR_NOSUCHCHANNEL, format!("Cannot join channel: {}", error))
+    }
+}
+
+/// Room of an IRC target: a channel known to the naming, or a user we
+/// have direct messages with
+async fn find_room<'a>(session: &'a mut Session, server: &mut Handle, naming: &Naming, target: &str) -> Option<&'a Room> {
+    match naming.rid(target) {
+        Some(rid) => session.room_by_id(&RoomID::from(rid.to_string())),
+        None if naming.is_channel(target) => None,
+        None => session.room_by_target(server, target).await,
+    }
+}
+
+fn build_userlist(user: &str, server: &str, channel: &str, members: Option<&Channel>) -> Vec<Message> {
 
-    let channel = format!("#{}", room_name);
     let mut output = Vec::new();
 
-    let mut userlist = String::new();
-    for person in users {
-        if userlist.len() > 512 {
-            output.push(server_response(server, user.into(),
-            Response::RPL_NAMREPLY, vec![modechar.into(), channel.clone(), userlist]));
-            userlist = String::new();
-        }
+    if let Some(Channel { modechar, users, .. }) = members {
+        let mut userlist = String::new();
+        for person in users {
+            if userlist.len() > 512 {
+                output.push(server_response(server, user.into(),
+                Response::RPL_NAMREPLY, vec![modechar.to_string(), channel.into(), userlist]));
+                userlist = String::new();
+            }
 
-        if userlist.len() > 0 { userlist += " "; }
-        userlist += &person.username;
+            if userlist.len() > 0 { userlist += " "; }
+            userlist += person;
 
-    }
+        }
 
-    if userlist.len() > 0 {
-        output.push(server_response(server, user.into(),
-            Response::RPL_NAMREPLY, vec![modechar.into(), channel.clone(), userlist]));
+        if userlist.len() > 0 {
+            output.push(server_response(server, user.into(),
+                Response::RPL_NAMREPLY, vec![modechar.to_string(), channel.into(), userlist]));
+        }
     }
 
     output.push(server_response(server, user.into(),
-     Response::RPL_ENDOFNAMES, vec![channel, "End of /NAMES list.".into()]));
+     Response::RPL_ENDOFNAMES, vec![channel.into(), "End of /NAMES list.".into()]));
 
     output
 
 }
 
-type IRCConn = Framed<TcpStream, IrcCodec>;
+/// WHO reply for a user, or WHOX reply (354) when fields are requested (`%<fields>[,<token>]`)
+fn who_reply(me: &str, server: &str, channel: &str, person: &User, whox: Option<&str>) -> Message {
+    let flags = if person.status.is_away() { "G" } else { "H" };
+    let nick = person.username.clone();
+    let fields = match whox {
+        None => return server_response(server, me.into(), Response::RPL_WHOREPLY,
+            vec![channel.into(), nick.clone(), server.into(), server.into(), nick, flags.into(), format!("0 {}", person.name)]),
+        Some(fields) => fields,
+    };
+
+    let mut parts = fields.trim_start_matches('%').splitn(2, ',');
+    let (fields, token) = (parts.next().unwrap_or(""), parts.next().unwrap_or("0"));
+    let mut args = vec![me.to_string()];
+    // fields are replied in this order, whatever the order of the request
+    for field in "tcuihsnfdlaor".chars().filter(|f| fields.contains(*f)) {
+        args.push(match field {
+            't' => token.into(),
+            'c' => channel.into(),
+            'u' | 'n' | 'a' => nick.clone(),
+            'i' => "255.255.255.255".into(),
+            'h' | 's' => server.into(),
+            'f' => flags.into(),
+            'd' | 'l' => "0".into(),
+            'o' => "n/a".into(),
+            _ => person.name.clone(),
+        });
+    }
+    Message { tags: None, prefix: Some(Prefix::ServerName(server.into())), command: Command::Raw("354".into(), args) }
+}
+
+type IRCConn<S> = Framed<S, Codec>;
 
 
-async fn respond(c: &mut IRCConn, code: irc_proto::Response, args: Vec<String>) -> Result<()> {
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(c: &mut IRCConn<S>, code: irc_proto::Response, args: Vec<String>) -> Result<()> {
     use irc_proto::Prefix::ServerName;
     Ok(c.send(Message {
         tags: None,
@@ -115,9 +174,28 @@ async fn respond(c: &mut IRCConn, code: irc_proto::Response, args: Vec<String>)
     }).await?)
 }
 
+async fn notice<S: AsyncRead + AsyncWrite + Unpin>(c: &mut IRCConn<S>, target: &str, text: &str) -> Result<()> {
+    Ok(c.send(Message {
+        tags: None,
+        prefix: Some(Prefix::ServerName("localhost".to_string())),
+        command: Command::NOTICE(target.to_string(), text.to_string())
+    }).await?)
+}
+
+const TOTP_PROMPT: &str = "Two-factor authentication required, please send your code with /QUOTE PASS <code>";
+
 
-async fn login(c: &mut IRCConn, host: String) -> Result<ClientInfo> {
+/// Run IRC registration until the client is authenticated, through SASL
+/// or PASS, and has a Rocket session to attach to
+async fn login<S: AsyncRead + AsyncWrite + Unpin>(c: &mut IRCConn<S>, host: String, config: &Arc<Config>, sessions: &Sessions) -> Result<(ClientInfo, SessionHandle)> {
     let (mut nick,mut user,mut pass) = (None, None, None);
+    let mut caps = Caps::default();
+    let mut sasl = Sasl::default();
+    let mut session = None;
+    // Password login waiting for its two-factor code
+    let mut pending_totp: Option<Login> = None;
+    // Registration is suspended from CAP LS or REQ until CAP END
+    let mut negotiating = false;
 
     loop {
         let input = c.next()
@@ -126,68 +204,1245 @@ async fn login(c: &mut IRCConn, host: String) -> Result<ClientInfo> {
 
         match input.command {
             Command::NICK(n) => { nick = Some(n) },
-            Command::PASS(p) => { pass = Some(p) },
+            Command::PASS(p) => {
+                pass = Some(match pending_totp.take() {
+                    Some(login) => login.with_totp(p),
+                    None => Login::parse(&p),
+                })
+            },
             Command::USER(u, _mode, _realname) => { user = Some(u) },
+            Command::CAP(_, CapSubCommand::END, _, _) => { negotiating = false },
+            Command::CAP(_, sub, arg, _) => {
+                negotiating = true;
+                let target = nick.as_deref().unwrap_or("*");
+                if let Some(reply) = caps.handle(target, sub, arg) {
+                    c.send(Message { tags: None, prefix: Some(Prefix::ServerName("localhost".into())), command: reply }).await?;
+                }
+            },
+            Command::AUTHENTICATE(_) if session.is_some() => {
+                respond(c, Response::ERR_SASLALREADY, vec![nick.clone().unwrap_or_else(|| "*".into()),
+                    "You have already authenticated".into()]).await?;
+            },
+            Command::AUTHENTICATE(data) => {
+                let target = nick.clone().unwrap_or_else(|| "*".into());
+                match sasl.step(&data) {
+                    SaslStep::Challenge => {
+                        c.send(Message { tags: None, prefix: None, command: Command::AUTHENTICATE("+".into()) }).await?;
+                    },
+                    SaslStep::More => {},
+                    SaslStep::Unsupported => {
+                        respond(c, Response::RPL_SASLMECHS, vec![target.clone(), "PLAIN".into(), "are available SASL mechanisms".into()]).await?;
+                        respond(c, Response::ERR_SASLFAIL, vec![target, "SASL authentication failed".into()]).await?;
+                    },
+                    SaslStep::Aborted => {
+                        respond(c, Response::ERR_SASLABORT, vec![target, "SASL authentication aborted".into()]).await?;
+                    },
+                    SaslStep::Invalid => {
+                        respond(c, Response::ERR_SASLFAIL, vec![target, "SASL authentication failed".into()]).await?;
+                    },
+                    SaslStep::Done(login) => {
+                        let info = ClientInfo { nick: target.clone(), user: user.clone().unwrap_or_else(|| target.clone()),
+                            login, host: host.clone(), caps: caps.clone() };
+                        let (info, result) = authenticate(c, info, config, sessions).await?;
+                        match result {
+                            Ok(handle) => {
+                                respond(c, Response::RPL_LOGGEDIN, vec![target.clone(), info.to_string(), handle.account.clone(),
+                                    format!("You are now logged in as {}", handle.account)]).await?;
+                                respond(c, Response::RPL_SASLSUCCESS, vec![target, "SASL authentication successful".into()]).await?;
+                                pass = Some(info.login);
+                                session = Some(handle);
+                            },
+                            Err(Refusal::TotpRequired) => {
+                                respond(c, Response::ERR_SASLFAIL, vec![target.clone(), Refusal::TotpRequired.to_string()]).await?;
+                                notice(c, &target, TOTP_PROMPT).await?;
+                                pending_totp = Some(info.login);
+                            },
+                            Err(refusal) => {
+                                respond(c, Response::ERR_SASLFAIL, vec![target, refusal.to_string()]).await?;
+                            },
+                        }
+                    },
+                }
+            },
             _ => { },
         }
 
-        match (&nick, &user, &pass) {
-            (Some(nick), Some(user), Some(pass)) => {
-                return Ok(ClientInfo { nick: nick.clone(), user: user.clone(), pass: pass.clone(), host });
+        if negotiating {
+            continue
+        }
+
+        let clientinfo = match (&nick, &user, &pass) {
+            (Some(nick), Some(user), Some(login)) => {
+                ClientInfo { nick: nick.clone(), user: user.clone(), login: login.clone(), host: host.clone(), caps: caps.clone() }
+            },
+            (Some(nick), Some(_), None) if pending_totp.is_none() => {
+                respond(c, Response::ERR_PASSWDMISMATCH, vec![nick.clone(), "Please send your rocket authentication token, or username:password".into()]).await?;
+                continue
             },
+            _ => continue,
+        };
+
+        let (clientinfo, result) = match session.take() {
+            Some(session) => (clientinfo, Ok(session)),
+            None => authenticate(c, clientinfo, config, sessions).await?,
+        };
 
-            (Some(nick), Some(_), None) => {
-                respond(c, Response::ERR_PASSWDMISMATCH, vec![nick.clone(), "Please send your rocket authentication token".into()]).await?;
+        match result {
+            Ok(session) => return Ok((clientinfo, session)),
+            Err(Refusal::TotpRequired) => {
+                notice(c, &clientinfo.nick, TOTP_PROMPT).await?;
+                pending_totp = pass.take();
+            },
+            Err(refusal) => {
+                respond(c, Response::ERR_PASSWDMISMATCH, vec![clientinfo.nick.clone(), refusal.to_string()]).await?;
+                return Err(anyhow!("Login failed: {}", refusal))
             },
-            _ => {},
         }
 
     }
 
 }
 
-async fn recover_username(c: &mut Rasta, id: &UserID) -> Result<String> {
+/// Acquire a session. If Rocket asks for a two-factor code, and the password
+/// ends with one (`password:123456`), try again with it split off.
+async fn authenticate<S>(c: &mut IRCConn<S>, clientinfo: ClientInfo, config: &Arc<Config>, sessions: &Sessions) -> Result<(ClientInfo, std::result::Result<SessionHandle, Refusal>)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let result = acquire_session(c, &clientinfo, config, sessions).await?;
+    if let (Err(Refusal::TotpRequired), Some(login)) = (&result, clientinfo.login.split_totp()) {
+        let split = ClientInfo { login, ..clientinfo.clone() };
+        // on failure, prompt for the code with the password as it was given
+        if let Ok(session) = acquire_session(c, &split, config, sessions).await? {
+            return Ok((split, Ok(session)))
+        }
+    }
+    Ok((clientinfo, result))
+}
+
+/// Log into Rocket, then attach to the running session of that user (in bouncer
+/// mode) or start a new one. Credentials are checked by Rocket on every attach,
+/// so that revoked tokens and two-factor authentication apply to running sessions too.
+/// Fails with the backend's reason if it rejected the credentials.
+async fn acquire_session<S>(c: &mut IRCConn<S>, clientinfo: &ClientInfo, config: &Arc<Config>, sessions: &Sessions) -> Result<std::result::Result<SessionHandle, Refusal>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let connected = match Proxy::connect(c, clientinfo, config).await? {
+        Ok(connected) => connected,
+        Err(refusal) => return Ok(Err(refusal)),
+    };
+
+    if !config.bouncer {
+        return Ok(Ok(Proxy::start(clientinfo, config.clone(), connected).await?))
+    }
+
+    let slot = sessions.slot(&connected.uid);
+    let mut slot = slot.lock().await;
+    match &*slot {
+        Some(session) if session.is_running() => {
+            // the new Rocket connection is dropped, the running session is used instead
+            info!("Reattaching {} to its running session", clientinfo);
+            Ok(Ok(session.clone()))
+        },
+        _ => {
+            let session = Proxy::start(clientinfo, config.clone(), connected).await?;
+            *slot = Some(session.clone());
+            Ok(Ok(session))
+        },
+    }
+}
+
+/// A Rocket connection logged in with the credentials of a client
+struct Connected {
+    back: Rasta,
+    /// Rocket user ID and username
+    uid: String,
+    nick: String,
+    token: String,
+}
+
+/// Wait for the backend to send our own user record, and return our (id, username)
+async fn recover_username(c: &mut Rasta, id: &UserID) -> Result<(String, String)> {
     loop {
         match c.recv().await? {
             ServerMessage::Added { collection, id: found, fields  } 
                 if &collection == "users" && id == &*found => {
-                    return Ok(fields.ok_or(anyhow!("fields was missing"))?
+                    let username = fields.ok_or(anyhow!("fields was missing"))?
                              .as_object().ok_or(anyhow!("fields wasn't an object"))?
                              .get("username").ok_or(anyhow!("username was missing"))?
                              .as_str().ok_or(anyhow!("username wasn't as string"))?
-                             .to_string()
-                    )
+                             .to_string();
+                    return Ok((found.to_string(), username))
                 },
             _ => {},
         }
     }
 }
 
+/// An IRC connection attached to a Rocket session
+pub struct Client {
+    id: usize,
+    info: ClientInfo,
+    tx: UnboundedSender<Message>,
+    /// draft/multiline batch being received
+    batch: Option<Incoming>,
+}
+
+impl Client {
+
+    /// Send a message, adapted to the capabilities of this client
+    fn send(&self, msg: Message) {
+        if let Some(msg) = self.info.caps.filter(msg) {
+            let _ = self.tx.unbounded_send(msg);
+        }
+    }
+
+}
+
+/// Sent by IRC connections to the Rocket session they are attached to
+pub enum Event {
+    Attach(Client),
+    Detach(usize),
+    Client(usize, Message),
+}
+
+/// Name of an IRC command, for error replies
+fn command_name(command: &Command) -> String {
+    match command {
+        Command::Raw(cmd, _) => cmd.to_ascii_uppercase(),
+        other => String::from(other).split_whitespace().next().unwrap_or("*").to_string(),
+    }
+}
+
+/// How long a direct message room that could not be looked up is ignored
+const ROOM_RETRY: Duration = Duration::from_secs(300);
+
+/// Length of abbreviated message IDs
+const SHORT_ID: usize = 4;
+
+/// Abbreviated message ID, used to refer to threads in plain text
+fn short_id(id: &str) -> &str {
+    &id[..id.len().min(SHORT_ID)]
+}
+
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Proxy {
+    config: Arc<Config>,
     clientinfo: ClientInfo,
     //userid: UserID,
     session: Session,
     server_up: Handle,
-    client_up: SplitSink<IRCConn, Message>,
+    clients: Vec<Client>,
+    backlog: Backlog,
     server_addr: String,
     message_cache: Cache<MessageID>,
+    members: Members,
+    art: Option<Renderer>,
+    preview_cache: Cache<MessageID>,
+    /// Target and text of recent messages, to show what changed when they are
+    /// edited, or which message was deleted
+    texts: Recent<MessageID, (String, String)>,
+    deleted: Cache<MessageID>,
+    /// Thread root of recent thread replies
+    threads: Recent<MessageID, String>,
+    /// Convert between markdown and IRC formatting codes. Set with FORMATTING
+    /// for all the clients of the user, and kept while the session lives.
+    formatting: bool,
+    /// Direct message rooms by ID, with their channel if they are group conversations
+    directs: HashMap<String, Option<String>>,
+    /// Direct message rooms we could not look up, and when, so that every
+    /// message in them doesn't retry the lookup
+    unknown_rooms: Recent<String, Instant>,
+    /// Reactions on recent messages, to tell which ones changed
+    reactions: Recent<MessageID, Reactions>,
+    /// Channel names of rooms
+    naming: Naming,
+    /// Last known presence of users, by username
+    presence: HashMap<String, Status>,
+    /// Lines of images rendered in the background
+    rendered: UnboundedSender<Vec<Message>>,
 }
 
 impl Proxy {
 
-    async fn respond(&mut self, code: Response, args: Vec<String>) -> Result<()> {
+    fn client(&self, id: usize) -> Option<&Client> {
+        self.clients.iter().find(|c| c.id == id)
+    }
+
+    fn send_to(&self, id: usize, msg: Message) {
+        if let Some(client) = self.client(id) {
+            client.send(msg);
+        }
+    }
+
+    fn respond(&self, id: usize, code: Response, args: Vec<String>) {
         let msg = server_response(&self.server_addr,
             self.clientinfo.nick.clone(), code, args);
-        Ok(self.client_up.send(msg).await?)
+        self.send_to(id, msg)
+    }
+
+    /// Reply with a numeric irc-proto has no name for
+    fn respond_raw(&self, id: usize, code: &str, mut args: Vec<String>) {
+        args.insert(0, self.clientinfo.nick.clone());
+        self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+            command: Command::Raw(code.into(), args) })
+    }
+
+    /// Send a message to all attached clients, or keep it for
+    /// later if none is attached
+    fn broadcast(&mut self, msg: Message) {
+        if self.clients.is_empty() {
+            self.backlog.push(msg);
+        }
+        for client in &self.clients {
+            client.send(msg.clone());
+        }
+    }
+
+    /// Text of a Rocket message as shown on IRC
+    fn render(&self, text: &str) -> String {
+        if self.formatting { format::to_irc(text) } else { text.to_string() }
+    }
+
+    /// Send a message rendered for the capabilities of each client. The
+    /// backlog gets the rendering for a client without any capability.
+    fn broadcast_each(&mut self, render: impl Fn(&Caps) -> Message) {
+        if self.clients.is_empty() {
+            self.backlog.push(render(&Caps::default()));
+        }
+        for client in &self.clients {
+            client.send(render(&client.info.caps));
+        }
+    }
+
+    /// Send a text, split in as many PRIVMSGs as needed for each client (see
+    /// `multiline::messages`). The text may also depend on client capabilities.
+    fn broadcast_text(&mut self, tags: Vec<Tag>, prefix: Option<Prefix>, target: &str, text: impl Fn(&Caps) -> String) {
+        if self.clients.is_empty() {
+            let caps = Caps::default();
+            for msg in multiline::messages(&tags, &prefix, target, &text(&caps), &caps) {
+                self.backlog.push(msg);
+            }
+        }
+        for client in &self.clients {
+            for msg in multiline::messages(&tags, &prefix, target, &text(&client.info.caps), &client.info.caps) {
+                client.send(msg);
+            }
+        }
+    }
+
+    /// NAMES reply of a channel, followed by the away status of its members
+    /// for clients with away-notify
+    fn names(&self, channel: &str) -> Vec<Message> {
+        let members = self.members.get(channel);
+        let mut output = build_userlist(&self.clientinfo.nick, &self.server_addr, channel, members);
+        for person in members.into_iter().flat_map(|chan| &chan.users).filter(|person| **person != self.clientinfo.nick) {
+            if let Some(message) = self.presence.get(person).and_then(Status::away_message) {
+                output.push(Message { tags: None,
+                    prefix: Some(Prefix::Nickname(person.clone(), person.clone(), self.server_addr.clone())),
+                    command: Command::AWAY(Some(message)) });
+            }
+        }
+        output
+    }
+
+    /// Fetch the current userlist of a channel from the backend
+    async fn refresh_members(&mut self, channel: &str) -> Result<()> {
+        // by room ID, as rooms we just joined or created are not in the session yet
+        let rid = match self.naming.rid(channel) {
+            Some(rid) => rid.to_string(),
+            None => return Ok(()),
+        };
+        let modechar = match self.naming.parse(channel) {
+            Some((Kind::Public, _)) => '=',
+            _ => '*',
+        };
+        let found = users::room_users(&mut self.server_up, &rid).await?;
+        for person in &found {
+            self.presence.insert(person.username.clone(), person.status.clone());
+        }
+        self.members.set(channel.into(), modechar, found.into_iter().map(|person| person.username));
+        Ok(())
+    }
+
+    /// Create a Rocket room for a channel that does not exist, with --create-channels
+    async fn create_room(&mut self, kind: Kind, name: &str) -> std::result::Result<RoomID, (Response, String)> {
+        let method = match kind {
+            Kind::Public => "createChannel",
+            Kind::Private => "createPrivateGroup",
+            _ => return Err((Response::ERR_NOSUCHCHANNEL, "Only public and private rooms can be created".into())),
+        };
+        match self.server_up.call(method, vec![json!(name), json!([]), json!(false)]).await {
+            Ok(room) => match room["rid"].as_str() {
+                Some(rid) => Ok(RoomID::from(rid.to_string())),
+                None => Err((Response::ERR_NOSUCHCHANNEL, "Could not create channel".into())),
+            },
+            Err(e) => Err((Response::ERR_NOSUCHCHANNEL, format!("Could not create channel: {}", e))),
+        }
+    }
+
+    /// Room ID of a channel, either a room or a group conversation
+    fn channel_rid(&self, channel: &str) -> Option<String> {
+        self.naming.rid(channel).map(str::to_string).or_else(|| self.group_rid(channel))
+    }
+
+    /// Fetch the messages replayed when joining channels, as configured with
+    /// --history and --history-unread. Rooms are fetched concurrently, and
+    /// those whose history can't be fetched are left out.
+    async fn histories(&mut self, channels: Vec<String>) -> Vec<(String, Vec<Value>)> {
+        let limit = match (self.config.history, self.config.history_unread) {
+            (0, false) => return vec![],
+            (0, true) => history::MAX_LIMIT,
+            (limit, _) => limit,
+        };
+        let last_read = match self.config.history_unread {
+            true => history::last_read(&mut self.server_up).await.unwrap_or_else(|e| {
+                warn!("Could not get the last read messages: {}", e);
+                HashMap::new()
+            }),
+            false => HashMap::new(),
+        };
+
+        let fetches: Vec<_> = channels.into_iter()
+            .filter_map(|channel| {
+                let rid = self.channel_rid(&channel)?;
+                let (mut server, since) = (self.server_up.clone(), last_read.get(&rid).copied());
+                Some(async move {
+                    let messages = history::recent(&mut server, &rid, since, limit).await;
+                    (channel, messages)
+                })
+            })
+            .collect();
+
+        futures::future::join_all(fetches).await.into_iter()
+            .filter_map(|(channel, messages)| match messages {
+                Ok(messages) => Some((channel, messages)),
+                Err(e) => {
+                    warn!("Could not load history of {}: {}", channel, e);
+                    None
+                },
+            })
+            .collect()
+    }
+
+    /// Replay the history of a channel we just joined to all attached clients
+    async fn backfill(&mut self, channel: &str) -> Result<()> {
+        if self.clients.is_empty() {
+            return Ok(())
+        }
+        let messages = match self.histories(vec![channel.to_string()]).await.pop() {
+            Some((_, messages)) => messages,
+            None => return Ok(()),
+        };
+        for client in &self.clients {
+            for msg in history::replay(&messages, channel, &self.clientinfo.nick, &self.server_addr, &client.info.caps, self.formatting) {
+                client.send(msg);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer a CHATHISTORY request: `<subcommand> <target> <reference>... <limit>`
+    async fn chathistory(&mut self, id: usize, args: Vec<String>) -> Result<()> {
+        let server = self.server_addr.clone();
+        let fail = |code: &str, context: &str, text: &str| Message { tags: None,
+            prefix: Some(Prefix::ServerName(server.clone())),
+            command: Command::Raw("FAIL".into(), vec!["CHATHISTORY".into(), code.into(), context.into(), text.into()]) };
+
+        let limit = args.last().and_then(|limit| limit.parse::<usize>().ok());
+        let (sub, target, refs, limit) = match (args.first(), args.get(1), limit) {
+            (Some(sub), Some(target), Some(limit)) if args.len() >= 4 =>
+                (sub.to_ascii_uppercase(), target.clone(), &args[2..args.len()-1], limit.min(history::MAX_LIMIT)),
+            _ => {
+                self.send_to(id, fail("NEED_MORE_PARAMS", "*", "Missing parameters"));
+                return Ok(())
+            },
+        };
+
+        let mut points = vec![];
+        for reference in refs.iter().filter(|r| *r != "*") {
+            if let Some(point) = history::resolve(&mut self.server_up, reference).await {
+                points.push(point);
+            }
+        }
+
+        let query = match (sub.as_str(), refs.len(), &points[..]) {
+            ("LATEST", 1, []) => history::Query::Latest(None),
+            ("LATEST", 1, [after]) => history::Query::Latest(Some(*after)),
+            ("BEFORE", 1, [before]) => history::Query::Before(*before),
+            ("AFTER", 1, [after]) => history::Query::After(*after),
+            ("AROUND", 1, [ts]) => history::Query::Around(*ts),
+            ("BETWEEN", 2, [start, end]) => history::Query::Between(*start, *end),
+            _ => {
+                self.send_to(id, fail("INVALID_PARAMS", &sub, "Invalid subcommand or message reference"));
+                return Ok(())
+            },
+        };
+
+        let rid = match find_room(&mut self.session, &mut self.server_up, &self.naming, &target).await {
+            Some(room) => Some(room.id().to_string()),
+            None => self.group_rid(&target),
+        };
+        let messages = match rid {
+            Some(rid) => Some(history::query(&mut self.server_up, &rid, query, limit).await),
+            None => None,
+        };
+
+        match messages {
+            None => self.send_to(id, fail("INVALID_TARGET", &sub, "No such channel or user")),
+            Some(Err(e)) => {
+                warn!("Could not load history of {}: {}", target, e);
+                self.send_to(id, fail("MESSAGE_ERROR", &sub, "Could not load history"));
+            },
+            Some(Ok(messages)) => if let Some(client) = self.client(id) {
+                for msg in history::replay(&messages, &target, &self.clientinfo.nick, &server, &client.info.caps, self.formatting) {
+                    client.send(msg);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Answer a LIST request from the Rocket directory, including rooms we are not in
+    async fn list(&mut self, id: usize, params: Option<String>) -> Result<()> {
+        let filter = directory::Filter::parse(params.as_deref());
+        let rooms = match directory::search(&mut self.server_up, &filter.search_text(&self.naming.chantypes())).await {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                warn!("Could not search the room directory: {}", e);
+                vec![]
+            },
+        };
+
+        self.respond(id, Response::RPL_LISTSTART, vec!["Channel".into(), "Users  Name".into()]);
+        for room in &rooms {
+            let (rid, (kind, name)) = match (room["_id"].as_str(), naming::describe(room)) {
+                (Some(rid), Some(described)) => (rid, described),
+                _ => continue,
+            };
+            let channel = self.naming.assign(rid, kind, name);
+            let users = directory::users(room);
+            if filter.matches(&channel, users) {
+                self.respond(id, Response::RPL_LIST, vec![channel, users.to_string(), directory::topic(room).into()]);
+            }
+        }
+        self.respond(id, Response::RPL_LISTEND, vec!["End of /LIST".into()]);
+        Ok(())
+    }
+
+    /// Answer a WHOIS request from the Rocket profile of the user
+    async fn whois(&mut self, id: usize, target: &str) -> Result<()> {
+        let found = match users::info(&mut self.server_up, target).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Could not get the profile of {}: {}", target, e);
+                None
+            },
+        };
+
+        match found {
+            None => self.respond(id, Response::ERR_NOSUCHNICK, vec![target.into(), "No such nick".into()]),
+            Some(person) => {
+                let nick = person.username.clone();
+                let server = self.server_addr.clone();
+                self.respond(id, Response::RPL_WHOISUSER, vec![nick.clone(), nick.clone(), server.clone(), "*".into(), person.name.clone()]);
+                self.respond(id, Response::RPL_WHOISSERVER, vec![nick.clone(), server, "Rocket.Chat".into()]);
+                if let Some(message) = person.status.away_message() {
+                    self.respond(id, Response::RPL_AWAY, vec![nick.clone(), message]);
+                }
+                let status = match person.status.text.as_str() {
+                    "" => format!("is {}", person.status.state),
+                    text => format!("is {}: {}", person.status.state, text),
+                };
+                self.respond_raw(id, "320", vec![nick.clone(), status]);
+                if !person.roles.is_empty() {
+                    self.respond_raw(id, "320", vec![nick.clone(), format!("has roles {}", person.roles.join(", "))]);
+                }
+                if let Some(timezone) = person.timezone() {
+                    self.respond_raw(id, "320", vec![nick.clone(), format!("is in timezone {}", timezone)]);
+                }
+
+                let mut channels: Vec<String> = self.members.channels()
+                    .filter(|(_, chan)| chan.users.contains(&nick))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                channels.sort();
+                if !channels.is_empty() {
+                    self.respond(id, Response::RPL_WHOISCHANNELS, vec![nick, channels.join(" ")]);
+                }
+            },
+        }
+        self.respond(id, Response::RPL_ENDOFWHOIS, vec![target.into(), "End of /WHOIS list".into()]);
+        Ok(())
+    }
+
+    /// Answer a WHO request on a channel or a user, with WHOX fields if requested
+    async fn who(&mut self, id: usize, mask: String, whox: Option<String>) -> Result<()> {
+        let rid = self.channel_rid(&mask);
+        let channel = if rid.is_some() { self.channel_name(&mask) } else { "*".into() };
+        let found = match rid {
+            Some(rid) => users::room_users(&mut self.server_up, &rid).await,
+            None if self.naming.is_channel(&mask) => Ok(vec![]),
+            None => users::info(&mut self.server_up, &mask).await.map(|found| found.into_iter().collect()),
+        };
+        let found = found.unwrap_or_else(|e| {
+            warn!("Could not list users of {}: {}", mask, e);
+            vec![]
+        });
+
+        for person in &found {
+            self.presence.insert(person.username.clone(), person.status.clone());
+            self.send_to(id, who_reply(&self.clientinfo.nick, &self.server_addr, &channel, person, whox.as_deref()));
+        }
+        self.respond(id, Response::RPL_ENDOFWHO, vec![mask, "End of /WHO list".into()]);
+        Ok(())
+    }
+
+    /// Record a presence change of another user, and tell clients with
+    /// away-notify when they go away or come back
+    fn update_presence(&mut self, user: String, status: Status) {
+        if user == self.clientinfo.nick {
+            return
+        }
+        let message = status.away_message();
+        // users we knew nothing about are taken to have been online
+        let before = self.presence.insert(user.clone(), status);
+        if before.and_then(|before| before.away_message()) == message {
+            return
+        }
+        // only for users we share a channel with
+        if self.members.channels().any(|(_, chan)| chan.users.contains(&user)) {
+            let msg = Message { tags: None,
+                prefix: Some(Prefix::Nickname(user.clone(), user, self.server_addr.clone())),
+                command: Command::AWAY(message) };
+            // not kept in the backlog, as it would be stale when replayed
+            for client in &self.clients {
+                client.send(msg.clone());
+            }
+        }
+    }
+
+    /// Set our Rocket status from an IRC AWAY command
+    async fn set_away(&mut self, id: usize, reason: Option<String>) -> Result<()> {
+        // `AWAY :` with an empty reason also means back
+        let reason = reason.filter(|reason| !reason.is_empty());
+        let (state, text) = match &reason {
+            Some(reason) => ("away", reason.as_str()),
+            None => ("online", ""),
+        };
+        if let Err(e) = self.server_up.call("setUserStatus", vec![json!(state), json!(text)]).await {
+            warn!("Could not set status: {}", e);
+            return Ok(())
+        }
+        match reason {
+            Some(_) => self.respond(id, Response::RPL_NOWAWAY, vec!["You have been marked as being away".into()]),
+            None => self.respond(id, Response::RPL_UNAWAY, vec!["You are no longer marked as being away".into()]),
+        }
+        Ok(())
+    }
+
+    /// Fetch userlists and topics of all the rooms we are in
+    async fn load_rooms(&mut self) -> Result<()> {
+        // discussions and teams can only be told apart in the full room records
+        let mut named = vec![];
+        match self.server_up.call("rooms/get", vec![]).await {
+            Ok(rooms) => for room in rooms.as_array().into_iter().flatten() {
+                match (room["_id"].as_str(), naming::describe(room)) {
+                    (Some(rid), Some((kind, name))) => named.push((rid.to_string(), kind, name.to_string())),
+                    (Some(rid), None) if room["t"] == "d" => { self.add_direct(rid, room); },
+                    _ => {},
+                }
+            },
+            Err(e) => warn!("Could not list rooms: {}", e),
+        }
+        for room in self.session.rooms() {
+            let rid = room.id().to_string();
+            if let Some((kind, name, _)) = room_channel(room) {
+                if !named.iter().any(|(known, _, _)| *known == rid) {
+                    named.push((rid, kind, name.to_string()));
+                }
+            }
+        }
+        self.naming.assign_all(named);
+
+        for room in self.session.rooms() {
+            if let Some((kind, name, modechar)) = room_channel(room) {
+                let channel = self.naming.assign(&room.id().to_string(), kind, name);
+                let users = self.server_up.get_room_users(room).await?;
+                debug!("Got userlist: {:?}", users);
+                self.members.set(channel.clone(), modechar, users.into_iter().map(|u| u.username));
+
+                if let Room::Chat { topic, .. } | Room::Private { topic, .. } = room {
+                    self.members.set_topic(&channel, topic.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a direct message room, and track it as a channel if it is a group
+    /// conversation. Returns the channel.
+    fn add_direct(&mut self, rid: &str, room: &Value) -> Option<String> {
+        let users: Vec<String> = room["usernames"].as_array().into_iter().flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect();
+        let channel = if users.len() > 2 {
+            let channel = groups::channel_name(&users, &self.clientinfo.nick);
+            self.members.set(channel.clone(), '*', users);
+            Some(channel)
+        } else {
+            None
+        };
+        self.directs.insert(rid.into(), channel.clone());
+        channel
+    }
+
+    /// Channel of a room we got an event from. Rooms not seen before are looked
+    /// up to name them; if that fails they are named after the event, but the
+    /// name is not kept.
+    async fn event_channel(&mut self, rid: &RoomID, room_type: char, name: &str) -> String {
+        let rid = rid.to_string();
+        if let Some(channel) = self.naming.channel(&rid) {
+            return channel.to_string()
+        }
+        // events don't tell discussions and teams apart, only the room record does
+        match self.server_up.call("getRoomById", vec![json!(rid)]).await {
+            Ok(room) => if let Some((kind, name)) = naming::describe(&room) {
+                return self.naming.assign(&rid, kind, name)
+            },
+            Err(e) => warn!("Could not look up room {}: {}", rid, e),
+        }
+        // not assigned, so that the next event can get the right name
+        let kind = if room_type == 'p' { Kind::Private } else { Kind::Public };
+        self.naming.base(kind, name)
+    }
+
+    /// ID of the room of a group conversation channel
+    fn group_rid(&self, channel: &str) -> Option<String> {
+        self.directs.iter()
+            .find(|(_, c)| c.as_deref().map_or(false, |c| c.eq_ignore_ascii_case(channel)))
+            .map(|(rid, _)| rid.clone())
+    }
+
+    /// Channel as we named it, for a channel as spelled by a client. Channel
+    /// names are case-insensitive, but members are kept by our spelling.
+    fn channel_name(&self, channel: &str) -> String {
+        let group = || self.group_rid(channel).and_then(|rid| self.directs.get(&rid).cloned().flatten());
+        self.naming.rid(channel).and_then(|rid| self.naming.channel(rid)).map(str::to_string)
+            .or_else(group)
+            .unwrap_or_else(|| channel.to_string())
+    }
+
+    /// Channel of a direct message room, if it is a group conversation. Rooms we
+    /// did not know yet are looked up, and joined if they are groups.
+    async fn direct_channel(&mut self, rid: &str) -> Result<Option<String>> {
+        if let Some(channel) = self.directs.get(rid) {
+            return Ok(channel.clone())
+        }
+
+        if let Some(failed) = self.unknown_rooms.get(&rid.to_string()) {
+            if failed.elapsed() < ROOM_RETRY {
+                return Ok(None)
+            }
+        }
+
+        let room = match self.server_up.call("getRoomById", vec![json!(rid)]).await {
+            Ok(room) => room,
+            Err(e) => {
+                warn!("Could not look up room {}: {}", rid, e);
+                self.unknown_rooms.insert(rid.to_string(), Instant::now());
+                return Ok(None)
+            },
+        };
+        self.unknown_rooms.remove(&rid.to_string());
+
+        let channel = self.add_direct(rid, &room);
+        if let Some(channel) = &channel {
+            self.broadcast(self.clientinfo.echo_back(Command::JOIN(channel.clone(), None, None)));
+            for msg in self.names(channel) {
+                self.broadcast(msg);
+            }
+        }
+        Ok(channel)
+    }
+
+    /// Open a group conversation with these users, and join its channel
+    async fn join_group(&mut self, id: usize, users: Vec<String>) -> Result<()> {
+        let channel = groups::channel_name(&users, &self.clientinfo.nick);
+        if self.members.get(&channel).is_some() {
+            return Ok(())
+        }
+
+        let room = match self.server_up.call("createDirectMessage", users.iter().map(|user| json!(user)).collect()).await {
+            Ok(room) => room,
+            Err(e) => {
+                warn!("Could not open group conversation {}: {}", channel, e);
+                self.respond(id, Response::ERR_NOSUCHCHANNEL, vec![channel, "No such channel".into()]);
+                return Ok(())
+            },
+        };
+        let rid = match room["rid"].as_str() {
+            Some(rid) => rid.to_string(),
+            None => return Ok(()),
+        };
+
+        let mut members = users;
+        members.push(self.clientinfo.nick.clone());
+        self.members.set(channel.clone(), '*', members);
+        self.directs.insert(rid, Some(channel.clone()));
+
+        self.broadcast(self.clientinfo.echo_back(Command::JOIN(channel.clone(), None, None)));
+        for msg in self.names(&channel) {
+            self.broadcast(msg);
+        }
+        Ok(())
     }
 
-    async fn run(sock: TcpStream, peer: SocketAddr, server_addr: String) -> Result<()> {
+    /// Apply a Rocket membership system message ("uj", "ul", "au", "ru") to
+    /// the userlist, and notify the clients if anything changed.
+    async fn handle_membership(&mut self, t: &str, channel: String, actor: String, msg: String) -> Result<()> {
+        // For "added by" and "removed by", the message holds the affected username
+        let subject = match t {
+            "au" | "ru" => msg,
+            _ => actor.clone(),
+        };
+        let myself = subject == self.clientinfo.nick;
+        let joining = t == "uj" || t == "au";
+
+        let changed = match (joining, myself) {
+            (true, false) => self.members.add(&channel, &subject),
+            (true, true) => self.members.get(&channel).is_none(),
+            (false, false) => self.members.remove(&channel, &subject),
+            (false, true) => self.members.forget(&channel),
+        };
+
+        if !changed {
+            return Ok(())
+        }
+
+        let (source, command) = match t {
+            "ru" => (&actor, Command::KICK(channel.clone(), subject.clone(), Some(format!("Removed by {}", actor)))),
+            "ul" => (&subject, Command::PART(channel.clone(), None)),
+            _ => (&subject, Command::JOIN(channel.clone(), None, None)),
+        };
+
+        let out = if source == &self.clientinfo.nick {
+            self.clientinfo.echo_back(command)
+        } else {
+            Message { tags: None, prefix: Some(Prefix::Nickname(source.clone(), source.clone(), self.server_addr.clone())), command }
+        };
+        self.broadcast(out);
+
+        if joining && myself {
+            self.refresh_members(&channel).await?;
+            for msg in self.names(&channel) {
+                self.broadcast(msg);
+            }
+            self.backfill(&channel).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Show the link previews of a message as ACTIONs. The backend usually resolves
+    /// previews after the message was posted, so this is called again on updates,
+    /// and only sends them once per message. Returns true if anything was sent.
+    fn send_previews(&mut self, id: &MessageID, message: &Value, target: &str, from: &Option<Prefix>) -> bool {
+        let previews: Vec<String> = message["urls"].as_array()
+            .map_or(vec![], |urls| urls.iter().filter_map(embed::describe_url).collect());
+
+        if previews.is_empty() || self.preview_cache.sent(id) {
+            return false
+        }
+        self.preview_cache.insert(id.clone());
+
+        for preview in previews {
+            self.broadcast(Message { tags: message_tags(message, false), prefix: from.clone(),
+                command: Command::PRIVMSG(target.into(), format!("\x01ACTION {}\x01", preview))});
+        }
+        true
+    }
+
+    /// Show an edited message, as a `+draft/edit` tagged message for clients with
+    /// message-tags, or as an ACTION quoting both versions otherwise. Returns false
+    /// if the text did not change since we last saw the message.
+    fn show_edit(&mut self, id: &MessageID, message: &Value, target: &str) -> bool {
+        let (text, edited_at) = match (message["msg"].as_str(), message["editedAt"]["$date"].as_i64()) {
+            (Some(text), Some(edited_at)) => (text.to_string(), edited_at),
+            _ => return false,
+        };
+
+        // messages from before we started are only known by their edit time
+        let old = self.texts.get(id).map(|(_, text)| text.clone());
+        let changed = match &old {
+            Some(old) => old != &text,
+            None => Utc::now().timestamp_millis() - edited_at < 60_000,
+        };
+        if !changed {
+            return false
+        }
+        self.texts.insert(id.clone(), (target.to_string(), text.clone()));
+
+        let editor = message["editedBy"]["username"].as_str()
+            .or_else(|| message["u"]["username"].as_str())
+            .unwrap_or("*").to_string();
+        let prefix = Some(Prefix::Nickname(editor.clone(), editor.clone(), self.server_addr.clone()));
+
+        let mut tags = vec![caps::time_tag(Some(edited_at)), Tag("account".into(), Some(editor))];
+        if let Some(original) = message["_id"].as_str() {
+            tags.push(Tag("+draft/edit".into(), Some(original.into())));
+        }
+
+        let text = self.render(&text);
+        // an ACTION can only take one line
+        let fallback = match old {
+            Some(old) => format!("\x01ACTION edited: {} -> {}\x01", embed::shorten(&old), text.replace('\n', " ")),
+            None => format!("\x01ACTION edited: {}\x01", text.replace('\n', " ")),
+        };
+
+        self.broadcast_text(tags, prefix, target, |caps| if caps.has("message-tags") { text.clone() } else { fallback.clone() });
+        true
+    }
+
+    /// Show reactions added or removed since we last saw a message, as `+draft/react`
+    /// (or `+draft/unreact`) TAGMSGs for clients with message-tags, or as ACTIONs
+    /// quoting the message otherwise. Returns false if reactions did not change.
+    fn show_reactions(&mut self, id: &MessageID, message: &Value, target: &str) -> bool {
+        let new = reactions::parse(message);
+        // a message we did not see live: all its reactions are shown, since we
+        // can't tell which one changed
+        let empty = Reactions::new();
+        let changes = reactions::diff(self.reactions.get(id).unwrap_or(&empty), &new);
+        self.reactions.insert(id.clone(), new);
+        if changes.is_empty() {
+            return false
+        }
+
+        let quote = self.texts.get(id).map(|(_, text)| embed::quote(text));
+        let msgid = message["_id"].as_str().unwrap_or_default().to_string();
+        let server = self.server_addr.clone();
+
+        for (emoji, user, added) in changes {
+            let prefix = Some(Prefix::Nickname(user.clone(), user.clone(), server.clone()));
+            let (tag, verb) = if added { ("+draft/react", "reacted with") } else { ("+draft/unreact", "removed reaction") };
+            let tags = vec![caps::time_tag(None), Tag("account".into(), Some(user)),
+                Tag(tag.into(), Some(reactions::to_unicode(&emoji).into())), Tag("+draft/reply".into(), Some(msgid.clone()))];
+            let fallback = match &quote {
+                Some(quote) => format!("\x01ACTION {} {} to \"{}\"\x01", verb, emoji, quote),
+                None => format!("\x01ACTION {} {}\x01", verb, emoji),
+            };
+
+            self.broadcast_each(|caps| if caps.has("message-tags") {
+                Message { tags: Some(tags.clone()), prefix: prefix.clone(), command: Command::Raw("TAGMSG".into(), vec![target.into()]) }
+            } else {
+                Message { tags: Some(tags.clone()), prefix: prefix.clone(), command: Command::PRIVMSG(target.into(), fallback.clone()) }
+            });
+        }
+        true
+    }
+
+    /// Show that a message was deleted, as a REDACT for clients with
+    /// draft/message-redaction, or as a NOTICE quoting it otherwise. Deletions are
+    /// reported both as "rm" messages and as removals, so each is only shown once.
+    /// Without a target, only messages we have seen recently can be shown.
+    fn show_deletion(&mut self, id: &MessageID, target: Option<String>, by: Option<String>) {
+        if self.deleted.sent(id) {
+            return
+        }
+        let (known, quote) = match self.texts.remove(id) {
+            Some((target, text)) => (Some(target), Some(embed::quote(&text))),
+            None => (None, None),
+        };
+        let target = match target.or(known) {
+            Some(target) => target,
+            None => {
+                debug!("Deleted message {:?} is unknown", id);
+                return
+            },
+        };
+        self.deleted.insert(id.clone());
+
+        let msgid = format!("{}", id);
+        let prefix = Some(match &by {
+            Some(user) => Prefix::Nickname(user.clone(), user.clone(), self.server_addr.clone()),
+            None => Prefix::ServerName(self.server_addr.clone()),
+        });
+        let notice = match (&by, quote) {
+            (Some(user), Some(quote)) => format!("{} deleted a message: \"{}\"", user, quote),
+            (Some(user), None) => format!("{} deleted a message", user),
+            (None, Some(quote)) => format!("Message deleted: \"{}\"", quote),
+            (None, None) => "Message deleted".to_string(),
+        };
+
+        self.broadcast_each(|caps| Message { tags: Some(vec![caps::time_tag(None)]), prefix: prefix.clone(),
+            command: if caps.has("draft/message-redaction") {
+                Command::Raw("REDACT".into(), vec![target.clone(), msgid.clone()])
+            } else {
+                Command::NOTICE(target.clone(), notice.clone())
+            }});
+    }
+
+    /// Delete a Rocket message, from a client REDACT. The backend only lets
+    /// users delete their own messages, unless they are moderators.
+    async fn redact(&mut self, id: usize, args: Vec<String>) -> Result<()> {
+        let (target, msgid) = match (args.get(0), args.get(1)) {
+            (Some(target), Some(msgid)) => (target.clone(), msgid.clone()),
+            _ => {
+                self.respond(id, Response::ERR_NEEDMOREPARAMS, vec!["REDACT".into(), "Not enough parameters".into()]);
+                return Ok(())
+            },
+        };
+
+        if let Err(e) = self.server_up.call("deleteMessage", vec![json!({ "_id": msgid })]).await {
+            warn!("Could not delete message {}: {}", msgid, e);
+            self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+                command: Command::Raw("FAIL".into(), vec!["REDACT".into(), "REDACT_FORBIDDEN".into(), target, msgid,
+                    "You are not allowed to delete this message".into()]) });
+        }
+        Ok(())
+    }
+
+    /// Prefix of a thread reply for clients without message-tags:
+    /// the short ID of the thread, and a quote of its first message
+    fn thread_prefix(&self, tmid: &str) -> String {
+        let reference = if self.config.thread_marker.is_empty() {
+            "thread".to_string()
+        } else {
+            format!("{}{}", self.config.thread_marker, short_id(tmid))
+        };
+        match self.texts.get(&MessageID::from(tmid.to_string())) {
+            Some((_, text)) => format!("[{}: {}]", reference, embed::quote(text)),
+            None => format!("[{}]", reference),
+        }
+    }
+
+    /// Find the thread a client message refers to in plain text, as a marker and
+    /// short ID at the start of the message. Returns the thread and the message
+    /// without the reference.
+    fn thread_reference(&self, target: &str, text: &str) -> Option<(String, String)> {
+        let marker = &self.config.thread_marker;
+        if marker.is_empty() {
+            return None
+        }
+        let rest = text.strip_prefix(marker.as_str())?;
+        let (short, text) = match rest.find(char::is_whitespace) {
+            Some(idx) => (&rest[..idx], rest[idx..].trim_start()),
+            None => (rest, ""),
+        };
+        if short.len() != SHORT_ID {
+            return None
+        }
+        // newest first; threads of the matching messages, which must all be the same
+        let mut roots = self.texts.iter().rev()
+            .filter(|(id, (t, _))| t.as_str() == target && format!("{}", id).starts_with(short))
+            .map(|(id, _)| self.threads.get(id).cloned().unwrap_or_else(|| format!("{}", id)));
+        let root = roots.next()?;
+        if roots.any(|other| other != root) {
+            return None
+        }
+        Some((root, text.to_string()))
+    }
+
+    /// Download and render an image in the background, the lines are
+    /// broadcast by the session loop when done
+    fn render_image(&self, art: Renderer, url: String, tags: Option<Vec<Tag>>, from: Option<Prefix>, target: String) {
+        let rendered = self.rendered.clone();
+        tokio::spawn(async move {
+            match art.render_url(&url).await {
+                Ok(lines) => {
+                    let _ = rendered.unbounded_send(lines.into_iter()
+                        .map(|line| Message { tags: tags.clone(), prefix: from.clone(), command: Command::PRIVMSG(target.clone(), line) })
+                        .collect());
+                },
+                Err(e) => warn!("Could not render image {}: {}", url, e),
+            }
+        });
+    }
+
+    /// Send a message from a client to Rocket, possibly in a thread, and show it
+    /// to the other clients attached to the session
+    async fn send_privmsg(&mut self, id: usize, tags: Option<Vec<Tag>>, target: String, payload: String) -> Result<()> {
+        let target = self.channel_name(&target);
+        let reply = tags.iter().flatten()
+            .find(|Tag(key, _)| key == "+draft/reply")
+            .and_then(|Tag(_, value)| value.clone());
+        // ACTIONs are sent like Rocket's /me, other CTCP queries are answered here
+        let (me, text) = match ctcp::parse(&payload) {
+            None => (false, payload.as_str()),
+            Some((command, text)) if command == "ACTION" => (true, text),
+            Some((command, arg)) => {
+                self.answer_ctcp(id, &target, &command, arg);
+                return Ok(())
+            },
+        };
+        let markdown = if self.formatting { format::to_markdown(text) } else { text.to_string() };
+        let (thread, text) = match reply {
+            Some(id) => (Some(id), markdown),
+            None => match self.thread_reference(&target, &markdown) {
+                Some((id, text)) => (Some(id), text),
+                None => (None, markdown),
+            },
+        };
+        // an ACTION is not addressed to anyone
+        let text = if me { format!("_{}_", text) } else { self.mention(&target, text) };
+        // replies to a reply go to the thread it belongs to
+        let tmid = thread.map(|id| self.threads.get(&MessageID::from(id.clone())).cloned().unwrap_or(id));
+
+        let rid = find_room(&mut self.session, &mut self.server_up, &self.naming, &target).await
+            .map(|room| json!(room.id()));
+        let rid = match rid.or_else(|| self.group_rid(&target).map(|rid| json!(rid))) {
+            Some(rid) => rid,
+            None if self.naming.is_channel(&target) => {
+                self.respond(id, Response::ERR_CANNOTSENDTOCHAN, vec![target, "Cannot send to channel".into()]);
+                return Ok(())
+            },
+            // first message to this user: open a direct message room with them
+            None => match self.server_up.call("createDirectMessage", vec![json!(target)]).await {
+                Ok(room) => room["rid"].clone(),
+                Err(e) => {
+                    debug!("Could not open direct messages with {}: {}", target, e);
+                    self.respond(id, Response::ERR_NOSUCHNICK, vec![target, "No such nick".into()]);
+                    return Ok(())
+                },
+            },
+        };
+
+        let msgid = self.message_cache.send();
+        let mut message = json!({ "_id": msgid, "rid": rid, "msg": text });
+        if let Some(tmid) = tmid {
+            message["tmid"] = json!(tmid);
+        }
+        if let Err(e) = self.server_up.call("sendMessage", vec![message]).await {
+            warn!("Could not send message to {}: {}", target, e);
+            let reason = format!("Cannot send message: {}", e);
+            if self.naming.is_channel(&target) {
+                self.respond(id, Response::ERR_CANNOTSENDTOCHAN, vec![target, reason]);
+            } else {
+                self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+                    command: Command::Raw("FAIL".into(), vec!["PRIVMSG".into(), "CANNOT_SEND".into(), target, reason]) });
+            }
+            return Ok(())
+        }
+
+        // other clients attached to the session should see it too
+        let prefix = Some((&self.clientinfo).into());
+        let tags = tags.unwrap_or_default();
+        for client in &self.clients {
+            if client.id != id || client.info.caps.has("echo-message") {
+                for msg in multiline::messages(&tags, &prefix, &target, &payload, &client.info.caps) {
+                    client.send(msg);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite IRC-style addressing (`nick: hello`, `nick, hello`, or without
+    /// the space) into a Rocket mention, if the nick is a member of the channel
+    fn mention(&self, target: &str, text: String) -> String {
+        let users = match self.members.get(target) {
+            Some(chan) => &chan.users,
+            None => return text,
+        };
+        // the nick is followed by `:` or `,` within the first word
+        let word = text.find(char::is_whitespace).unwrap_or(text.len());
+        let end = text[..word].match_indices(|c| c == ':' || c == ',')
+            .map(|(end, _)| end)
+            .find(|end| users.contains(&text[..*end]));
+        match end {
+            Some(end) => format!("@{} {}", &text[..end], text[end+1..].trim_start()),
+            None => text,
+        }
+    }
+
+    /// Make Rocket mentions of us (`@all`, `@here` or our username) highlight
+    /// on IRC, where clients look for our nick, by addressing the message to it.
+    /// Not for ACTIONs, which can't be addressed.
+    fn highlight(&self, text: String) -> String {
+        let nick = &self.clientinfo.nick;
+        let mentioned = text.split(|c: char| c.is_whitespace() || ",:;!?()".contains(c))
+            .filter_map(|word| word.strip_prefix('@'))
+            .any(|name| name == "all" || name == "here" || name == nick);
+        let addressed = text.strip_prefix('@').unwrap_or(&text).starts_with(nick.as_str());
+        if mentioned && !addressed {
+            format!("{}: {}", nick, text)
+        } else {
+            text
+        }
+    }
+
+    /// Text of a Rocket message as shown on IRC, and its variant for clients
+    /// without message-tags, which quotes the thread it replies to
+    fn compose(&self, text: String, me: bool, own: bool, tmid: Option<&str>) -> (String, String) {
+        let text = if own || me { text } else { self.highlight(text) };
+        let plain = match tmid {
+            Some(tmid) => format!("{} {}", self.thread_prefix(tmid), text),
+            None => text.clone(),
+        };
+        if me { (ctcp::action(&text), ctcp::action(&plain)) } else { (text, plain) }
+    }
+
+    /// Answer a CTCP query to a Rocket user or room, on their behalf
+    fn answer_ctcp(&self, id: usize, target: &str, command: &str, arg: &str) {
+        let reply = match ctcp::answer(command, arg) {
+            Some(reply) => reply,
+            None => {
+                debug!("Ignoring CTCP {} to {}", command, target);
+                return
+            },
+        };
+        let prefix = if self.naming.is_channel(target) {
+            Prefix::ServerName(self.server_addr.clone())
+        } else {
+            Prefix::Nickname(target.into(), target.into(), self.server_addr.clone())
+        };
+        self.send_to(id, Message { tags: None, prefix: Some(prefix),
+            command: Command::NOTICE(self.clientinfo.nick.clone(), reply) });
+    }
+
+    /// Set or remove a Rocket reaction, from a client TAGMSG carrying
+    /// `+draft/react` (or `+draft/unreact`) and `+draft/reply`
+    async fn send_reaction(&mut self, id: usize, tags: Vec<Tag>) -> Result<()> {
+        let tag = |name: &str| tags.iter().find(|Tag(key, _)| key == name).and_then(|Tag(_, value)| value.clone());
+
+        let msgid = match tag("+draft/reply") {
+            Some(msgid) => msgid,
+            None => return Ok(()),
+        };
+        let (emoji, react) = match (tag("+draft/react"), tag("+draft/unreact")) {
+            (Some(emoji), _) => (emoji, true),
+            (None, Some(emoji)) => (emoji, false),
+            _ => return Ok(()),
+        };
+        let server = self.server_addr.clone();
+        let fail = |code: &str, text: String| Message { tags: None,
+            prefix: Some(Prefix::ServerName(server.clone())),
+            command: Command::Raw("FAIL".into(), vec!["TAGMSG".into(), code.into(), msgid.clone(), text]) };
 
-        let mut client = irc_proto::IrcCodec::new("utf8")?
-            .framed(sock);
+        let shortcode = match reactions::to_shortcode(&emoji) {
+            Some(shortcode) => shortcode,
+            None => {
+                warn!("No Rocket emoji for reaction {}", emoji);
+                self.send_to(id, fail("UNKNOWN_EMOJI", format!("No Rocket emoji for {}", emoji)));
+                return Ok(())
+            },
+        };
 
-        let mut clientinfo = login(&mut client, peer.ip().to_string()).await?;
-        debug!("Client identified as {}", clientinfo);
+        if let Err(e) = self.server_up.call("setReaction", vec![json!(shortcode), json!(msgid), json!(react)]).await {
+            warn!("Could not set reaction {} on {}: {}", shortcode, msgid, e);
+            self.send_to(id, fail("REACTION_FAILED", format!("Could not set reaction {}: {}", shortcode, e)));
+        }
+        Ok(())
+    }
+
+    /// Connect to Rocket and log in with the credentials of a client. Fails
+    /// with the reason the backend rejected the credentials.
+    async fn connect<S>(client: &mut IRCConn<S>, clientinfo: &ClientInfo, config: &Config) -> Result<std::result::Result<Connected, Refusal>>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+
+        let server_addr = config.backend.clone();
 
         let server_notice = |msg| {
             Message { tags: None, prefix: Some(Prefix::ServerName(server_addr.to_string()))
@@ -201,88 +1456,157 @@ impl Proxy {
         info!("Backend connected");
         client.send(server_notice("Backend connected".into())).await?;
 
-        respond(&mut client, Response::RPL_WELCOME,
-            vec![clientinfo.nick.clone(), format!("Welcome to IRC {}", &clientinfo)]).await?;
-        respond(&mut client, Response::RPL_YOURHOST,
-            vec![clientinfo.nick.clone(), format!("Your host is {}, running croquette v{}", server_addr, env!("CARGO_PKG_VERSION"))]).await?;
-        //respond(&mut client, Response::RPL_CREATED,
-        //     vec![clientinfo.nick.clone(), ??? ]).await?;
-        //respond(&mut client, Response::RPL_MYINFO,
-        //     vec![clientinfo.nick.clone(), ??? ]).await?;
-
-
-
-        let userid: UserID = match back.login(Credentials::from(clientinfo.pass.clone())).await? {
-            None => {
-                respond(&mut client, Response::ERR_PASSWDMISMATCH,
-                vec![
-                    clientinfo.nick.clone(),
-                    "Backend server rejected token".to_string(),
-                    ]).await?;
-                return Err(anyhow!("Login failed."))
-            },
-            Some(login) => {
-                login.id
+        let (userid, token): (UserID, String) = match back.login(clientinfo.login.credentials()).await? {
+            Err(e) => {
+                let refusal = Refusal::from(e);
+                warn!("Backend rejected credentials of {}: {}", clientinfo, refusal);
+                return Ok(Err(refusal))
+            },
+            Ok(login) => {
+                (login.id, login.token)
             }
         };
 
         client.send(server_notice(format!("Logged in successfully as {:?}", userid))).await?;
 
 
-        let nick = recover_username(&mut back, &userid).await?;
-        client.send(clientinfo.echo_back(Command::NICK(nick.clone()))).await?;
-        clientinfo.nick = nick;
+        let (uid, nick) = recover_username(&mut back, &userid).await?;
+        Ok(Ok(Connected { back, uid, nick, token }))
+    }
+
+    /// Start the session of a logged in user, and spawn the session task.
+    /// Returns the handle used to attach clients to the session.
+    async fn start(clientinfo: &ClientInfo, config: Arc<Config>, connected: Connected) -> Result<SessionHandle> {
+        let Connected { mut back, uid, nick, token } = connected;
+        let server_addr = config.backend.clone();
 
-        let mut server_up = back.handle();
+        let server_up = back.handle();
         let session = Session::from(&mut back).await?;
 
-        for room in session.rooms() {
-            match room {
-                Room::Chat { name, topic, ..} |
-                Room::Private { name, topic, ..} => {
-                    let channel_name= format!("#{}", name);
-                    client.send(clientinfo.echo_back(Command::JOIN(channel_name.clone(), None, None))).await?;
-                    if let Some(topic) = topic {
-                        respond(&mut client, Response::RPL_TOPIC, vec![clientinfo.nick.to_string(), channel_name, topic.clone()]).await?;
-                    }
+        let art = match &config.art {
+            Some(art) => Some(Renderer::new(&server_addr, uid, token, art.clone())?),
+            None => None,
+        };
 
-                    let users = server_up.get_room_users(room).await?;
+        let backlog = Backlog::new(if config.bouncer { config.backlog } else { 0 });
+        let account = nick.clone();
+        let clientinfo = ClientInfo { nick, ..clientinfo.clone() };
 
-                    debug!("Got userlist: {:?}", users);
+        let naming = Naming::new(config.prefixes.clone());
+        let formatting = config.formatting;
+        let (rendered, rendered_down) = mpsc::unbounded();
+        let mut proxy = Proxy { config, clientinfo, /*userid,*/ session,
+            server_up, clients: Vec::new(), backlog, server_addr, message_cache: Cache::new(MessageID::new, 256),
+            members: Members::new(), art, preview_cache: Cache::new(MessageID::new, 256), texts: Recent::new(1024), deleted: Cache::new(MessageID::new, 256), threads: Recent::new(1024), formatting, directs: HashMap::new(), unknown_rooms: Recent::new(256),
+            reactions: Recent::new(1024), naming, presence: HashMap::new(), rendered };
 
-                    for msg in build_userlist(&clientinfo.nick, &server_addr, &room, &users) {
-                        client.feed(msg).await?;
-                    }
-                    client.flush().await?;
+        proxy.load_rooms().await?;
+        back.subscribe_my_messages().await?;
+        back.subscribe("stream-notify-logged", vec![json!("user-status"), json!(false)]).await?;
 
-                },
-                _ => {},
+        let (events, events_down) = mpsc::unbounded();
+        tokio::spawn(async move {
+            match proxy.run(back, events_down, rendered_down).await {
+                Err(e) => error!("Rocket session terminated with error: {:?}", e),
+                _ => info!("Rocket session closed"),
             }
+        });
+
+        Ok(SessionHandle { events, account })
+    }
+
+    /// Bring a newly attached client up to date: registration, channels, and
+    /// whatever was received while no client was attached
+    async fn attach(&mut self, client: Client) -> Result<()> {
+        let nick = self.clientinfo.nick.clone();
+
+        let mut burst = vec![
+            server_response(&self.server_addr, client.info.nick.clone(), Response::RPL_WELCOME,
+                vec![format!("Welcome to IRC {}", &client.info)]),
+            server_response(&self.server_addr, client.info.nick.clone(), Response::RPL_YOURHOST,
+                vec![format!("Your host is {}, running croquette v{}", self.server_addr, env!("CARGO_PKG_VERSION"))]),
+            //RPL_CREATED, RPL_MYINFO ???
+            server_response(&self.server_addr, client.info.nick.clone(), Response::RPL_ISUPPORT,
+                vec![format!("CHATHISTORY={}", history::MAX_LIMIT), format!("CHANTYPES={}", self.naming.chantypes()), "ELIST=MU".into(), "WHOX".into(), "are supported by this server".into()]),
+        ];
+
+        if client.info.nick != nick {
+            burst.push(client.info.echo_back(Command::NICK(nick.clone())));
         }
 
-        back.subscribe_my_messages().await?;
+        for (channel, chan) in self.members.channels() {
+            burst.push(self.clientinfo.echo_back(Command::JOIN(channel.clone(), None, None)));
+            if let Some(topic) = &chan.topic {
+                burst.push(server_response(&self.server_addr, nick.clone(), Response::RPL_TOPIC,
+                    vec![channel.clone(), topic.clone()]));
+            }
+            burst.extend(build_userlist(&nick, &self.server_addr, channel, Some(chan)));
+        }
 
-        let (client_up, client_down) = client.split();
-        let mut client_down = client_down.fuse();
+        // messages still in the backlog are replayed below, with their own tags
+        let channels: Vec<String> = self.members.channels().map(|(channel, _)| channel.clone()).collect();
+        for (channel, messages) in self.histories(channels).await {
+            let messages: Vec<Value> = messages.into_iter()
+                .filter(|m| !m["_id"].as_str().map_or(false, |id| self.backlog.contains(id)))
+                .collect();
+            burst.extend(history::replay(&messages, &channel, &nick, &self.server_addr, &client.info.caps, self.formatting));
+        }
 
+        burst.extend(self.backlog.drain());
 
-        let mut server_down = back.stream().fuse();
+        for msg in burst {
+            client.send(msg);
+        }
+
+        info!("Client {} attached to the session of {}", client.info, nick);
+        self.clients.push(client);
+        Ok(())
+    }
 
-        let mut proxy = Proxy { clientinfo, /*userid,*/ session,
-            server_up, client_up, server_addr, message_cache: Cache::new(MessageID::new, 256) };
+    fn detach(&mut self, id: usize) {
+        self.clients.retain(|c| c.id != id);
+        debug!("Client {} detached, {} remaining", id, self.clients.len());
+    }
+
+    async fn run(mut self, mut back: Rasta, mut events: UnboundedReceiver<Event>, mut rendered: UnboundedReceiver<Vec<Message>>) -> Result<()> {
+
+        let mut server_down = back.stream().fuse();
 
         loop {
 
             select! {
 
-                msg = client_down.next() => {
-                    let msg = msg.ok_or(anyhow!("Client closed connection"))??;
-                    proxy.handle_client_message(msg).await?;
+                event = events.next() => match event {
+                    // a failed attach drops the client, which disconnects it
+                    Some(Event::Attach(client)) => if let Err(e) = self.attach(client).await {
+                        error!("Could not attach client: {:?}", e);
+                    },
+                    Some(Event::Detach(id)) => {
+                        self.detach(id);
+                        if self.clients.is_empty() && !self.config.bouncer {
+                            return Ok(())
+                        }
+                    },
+                    Some(Event::Client(id, msg)) => {
+                        let command = command_name(&msg.command);
+                        if let Err(e) = self.handle_client_message(id, msg).await {
+                            warn!("{} from client {} failed: {:?}", command, id, e);
+                            self.respond_raw(id, "400", vec![command, format!("Command failed: {}", e)]);
+                        }
+                    },
+                    None => return Ok(()),
+                },
+
+                lines = rendered.next() => for msg in lines.into_iter().flatten() {
+                    self.broadcast(msg);
                 },
 
                 msg = server_down.next() => {
+                    // only losing the Rocket connection ends the session
                     let msg = msg.ok_or(anyhow!("Server closed connection"))?;
-                    proxy.handle_server_message(msg).await?;
+                    if let Err(e) = self.handle_server_message(msg).await {
+                        warn!("Could not handle Rocket event: {:?}", e);
+                    }
                 },
 
             }
@@ -294,80 +1618,220 @@ impl Proxy {
 
 
 
-    async fn handle_client_message(&mut self, msg: Message) -> Result<()> {
+    async fn handle_client_message(&mut self, id: usize, msg: Message) -> Result<()> {
         match msg {
             Message { command: Command::JOIN(chanlist, keys, _), ..} => {
                 let chanlist = chanlist.split(",");
                 let keys = keys.as_ref().map(|k| k.split(",").map(str::to_owned));
                 for (chan, key) in lazy_zip(chanlist, keys) {
-                    if let Some(name) = chan.strip_prefix('#') {
-                        debug!("Joining {} with key {:?}", chan, key);
-                        if let Some(rid) = self.server_up.lookup_room_id(name.into()).await? {
-                            if self.server_up.join_room(rid, key).await? {
-                                self.client_up.send(self.clientinfo.echo_back(Command::JOIN(chan.into(), None, None))).await?
-                         }
-                        } else {
-                            warn!("Room not found: {}", chan);
+                    let known = self.naming.rid(chan).map(|rid| RoomID::from(rid.to_string()));
+                    if known.is_none() {
+                        if let Some(users) = groups::usernames(chan) {
+                            self.join_group(id, users).await?;
+                            continue
                         }
-                    } else {
-                        warn!("Other room types not supported");
+                    }
+                    let (kind, name) = match self.naming.parse(chan) {
+                        Some(parsed) => parsed,
+                        None => {
+                            self.respond(id, Response::ERR_NOSUCHCHANNEL, vec![chan.into(), "Invalid channel name".into()]);
+                            continue
+                        },
+                    };
+                    debug!("Joining {} with key {:?}", chan, key);
+                    let rid = match known {
+                        Some(rid) => Ok(Some(rid)),
+                        None => self.server_up.lookup_room_id(name.clone()).await,
+                    };
+                    let joined = match rid {
+                        Ok(Some(rid)) => match self.server_up.join_room(rid.clone(), key).await {
+                            Ok(true) => Ok(rid),
+                            Ok(false) => Err((Response::ERR_INVITEONLYCHAN, "Cannot join channel (+i)".into())),
+                            Err(e) => Err(join_error(&e.to_string())),
+                        },
+                        Ok(None) if self.config.create_channels => self.create_room(kind, &name).await,
+                        Ok(None) => Err((Response::ERR_NOSUCHCHANNEL, "No such channel".into())),
+                        Err(e) => Err(join_error(&e.to_string())),
+                    };
+                    match joined {
+                        Ok(rid) => {
+                            let chan = self.naming.assign(&rid.to_string(), kind, &name);
+                            self.broadcast(self.clientinfo.echo_back(Command::JOIN(chan.clone(), None, None)));
+                            self.refresh_members(&chan).await?;
+                            for msg in self.names(&chan) {
+                                self.broadcast(msg);
+                            }
+                            self.backfill(&chan).await?;
+                        },
+                        Err((code, reason)) => {
+                            warn!("Could not join {}: {}", chan, reason);
+                            self.respond(id, code, vec![chan.into(), reason]);
+                        },
                     }
                 }
             },
 
             Message { command: Command::NICK(_),..} => {
-                self.respond(Response::ERR_NICKNAMEINUSE, vec!["Can't change your nick in rocket, sorry :(".into()]).await?
+                self.respond(id, Response::ERR_NICKNAMEINUSE, vec!["Can't change your nick in rocket, sorry :(".into()])
             },
 
             Message { command: Command::PART(channels, _reason),..} => {
                 for chan in channels.split(",") {
-                    if let Some(name) = chan.strip_prefix('#') {
-                        if let Some(rid) = self.server_up.lookup_room_id(name.into()).await? {
-                            if self.server_up.leave_room(rid).await? {
-                                self.client_up.send(self.clientinfo.echo_back(Command::PART(chan.into(), None))).await?
-                            }
+                    let chan = &self.channel_name(chan);
+                    if let Some(rid) = self.naming.rid(chan).map(|rid| RoomID::from(rid.to_string())) {
+                        if self.server_up.leave_room(rid).await? {
+                            self.members.forget(chan);
+                            self.broadcast(self.clientinfo.echo_back(Command::PART(chan.into(), None)))
                         }
+                    } else if let Some(rid) = self.group_rid(chan) {
+                        // group conversations can't be left, only hidden until the next message
+                        if let Err(e) = self.server_up.call("hideRoom", vec![json!(rid)]).await {
+                            warn!("Could not hide room {}: {}", rid, e);
+                            self.respond(id, Response::ERR_NOTONCHANNEL, vec![chan.into(), format!("Cannot leave channel: {}", e)]);
+                            continue
+                        }
+                        self.directs.remove(&rid);
+                        self.members.forget(chan);
+                        self.broadcast(self.clientinfo.echo_back(Command::PART(chan.into(), None)))
                     }
                 }
 
             },
 
+            Message { command: Command::NAMES(channels, _), ..} => {
+                match channels {
+                    Some(channels) => for chan in channels.split(",") {
+                        for msg in self.names(&self.channel_name(chan)) {
+                            self.send_to(id, msg);
+                        }
+                    },
+                    None => self.respond(id, Response::RPL_ENDOFNAMES,
+                        vec!["*".into(), "End of /NAMES list.".into()]),
+                }
+            },
+
+            Message { command: Command::LIST(params, _), ..} => {
+                self.list(id, params).await?;
+            },
+
             Message { command: Command::PING(a,b), ..} => {
-                self.client_up.send(Message { tags: None,
+                self.send_to(id, Message { tags: None,
                     prefix: Some(Prefix::ServerName(self.server_addr.clone())),
                     command: Command::PONG(a,b)
-                }).await?
+                })
             },
 
-            Message { command: Command::PRIVMSG(target, payload),..} => {
-                let room = self.session.room_by_target(&mut self.server_up, &target).await;
-                let room = match room {
-                    Some(room) => room,
+            Message { tags, command: Command::PRIVMSG(target, payload),..} => {
+                let batch = tags.iter().flatten()
+                    .find(|Tag(key, _)| key == "batch")
+                    .and_then(|Tag(_, value)| value.clone());
+                let concat = tags.iter().flatten().any(|Tag(key, _)| key == "draft/multiline-concat");
+
+                match batch {
+                    Some(reference) => if let Some(client) = self.clients.iter_mut().find(|c| c.id == id) {
+                        let pushed = match &mut client.batch {
+                            Some(incoming) if incoming.reference == reference => incoming.push(&payload, concat),
+                            _ => {
+                                warn!("Message in unknown batch {}", reference);
+                                self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+                                    command: Command::Raw("FAIL".into(), vec!["BATCH".into(), "INVALID_REFTAG".into(), reference,
+                                        "Message in an unknown batch".into()]) });
+                                return Ok(())
+                            },
+                        };
+                        // the rest of the batch is ignored
+                        if let Err((code, limit)) = pushed {
+                            client.batch = None;
+                            self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+                                command: Command::Raw("FAIL".into(), vec!["BATCH".into(), code.into(), limit.to_string(),
+                                    format!("Multiline batch is over the limit of {}", limit)]) });
+                        }
+                    },
+                    None => self.send_privmsg(id, tags, target, payload).await?,
+                }
+            },
+            Message { tags, command: Command::Raw(cmd, args), ..} if cmd.eq_ignore_ascii_case("BATCH") => {
+                let client = match self.clients.iter_mut().find(|c| c.id == id) {
+                    Some(client) => client,
                     None => return Ok(()),
                 };
-
-                let id = self.message_cache.send();
-                self.server_up.send_message(id, room, payload).await?;
-
-
+                let reference = args.first().map(String::as_str).unwrap_or_default();
+                let complete = match (reference.strip_prefix('+'), reference.strip_prefix('-'), args.get(1), args.get(2)) {
+                    (Some(reference), _, Some(kind), Some(target)) if kind.eq_ignore_ascii_case("draft/multiline") => {
+                        client.batch = Some(Incoming::new(reference.into(), target.clone(), tags));
+                        None
+                    },
+                    (_, Some(reference), _, _) => client.batch.take().filter(|batch| batch.reference == reference),
+                    _ => None,
+                };
+                if let Some(batch) = complete {
+                    self.send_privmsg(id, batch.tags, batch.target, batch.text).await?;
+                }
             },
             Message { command: Command::TOPIC(target, topic),..} => {
-                let session = &mut self.session;
-                if let Some(room) = target.strip_prefix('#')
-                    .and_then(|chan| session.room_by_name(chan)) {
-                        if self.server_up.set_topic(room, topic.clone()).await? {
-                            self.client_up.send(self.clientinfo.echo_back(Command::TOPIC(target, topic))).await?
-                        }
+                let target = self.channel_name(&target);
+                let room = match self.naming.rid(&target) {
+                    Some(_) => find_room(&mut self.session, &mut self.server_up, &self.naming, &target).await,
+                    None => None,
+                };
+                let updated = match room {
+                    Some(room) => self.server_up.set_topic(room, topic.clone()).await?,
+                    None => false,
+                };
+                if updated {
+                    self.members.set_topic(&target, topic.clone());
+                    self.broadcast(self.clientinfo.echo_back(Command::TOPIC(target, topic)))
+                }
+            },
+            Message { command: Command::CAP(_, sub, arg, _), ..} => {
+                let (nick, server) = (self.clientinfo.nick.clone(), self.server_addr.clone());
+                if let Some(client) = self.clients.iter_mut().find(|c| c.id == id) {
+                    if let Some(reply) = client.info.caps.handle(&nick, sub, arg) {
+                        client.send(Message { tags: None, prefix: Some(Prefix::ServerName(server)), command: reply });
                     }
+                }
+            },
+            Message { command: Command::AUTHENTICATE(_), ..} => {
+                self.respond(id, Response::ERR_SASLALREADY, vec!["You have already authenticated".into()]);
+            },
+            Message { command: Command::Raw(cmd, args), ..} if cmd.eq_ignore_ascii_case("CHATHISTORY") => {
+                self.chathistory(id, args).await?;
+            },
+            Message { command: Command::Raw(cmd, args), ..} if cmd.eq_ignore_ascii_case("FORMATTING") => {
+                match args.first().map(|arg| arg.to_ascii_uppercase()).as_deref() {
+                    Some("ON") => self.formatting = true,
+                    Some("OFF") => self.formatting = false,
+                    _ => {},
+                }
+                let state = if self.formatting { "on" } else { "off" };
+                self.send_to(id, Message { tags: None, prefix: Some(Prefix::ServerName(self.server_addr.clone())),
+                    command: Command::NOTICE(self.clientinfo.nick.clone(), format!("Formatting conversion is {}", state)) });
+            },
+            Message { command: Command::Raw(cmd, args), ..} if cmd.eq_ignore_ascii_case("REDACT") => {
+                self.redact(id, args).await?;
+            },
+            Message { tags, command: Command::Raw(cmd, _), ..} if cmd.eq_ignore_ascii_case("TAGMSG") => {
+                self.send_reaction(id, tags.unwrap_or_default()).await?;
+            },
+            Message { command: Command::WHOIS(_, nicks), ..} => {
+                for nick in nicks.split(",") {
+                    self.whois(id, nick).await?;
+                }
+            },
+            Message { command: Command::WHO(Some(mask), _), ..} => {
+                self.who(id, mask, None).await?;
+            },
+            Message { command: Command::Raw(cmd, mut args), ..} if cmd.eq_ignore_ascii_case("WHO") && args.len() == 2 => {
+                let fields = args.pop();
+                self.who(id, args.remove(0), fields).await?;
             },
             Message { command: Command::AWAY(reason),..} => {
-                self.server_up.set_away(reason.is_some()).await?;
+                self.set_away(id, reason).await?;
             },
             other => {
                 warn!("Unsupported IRC command: {:?}", other);
-                self.respond(Response::ERR_UNKNOWNCOMMAND,
-                    vec!["Command unsupported by Croquette".into()])
-                    .await?;
+                self.respond(id, Response::ERR_UNKNOWNCOMMAND,
+                    vec!["Command unsupported by Croquette".into()]);
             },
         };
         Ok(())
@@ -375,8 +1839,17 @@ impl Proxy {
 
     async fn handle_server_message(&mut self, msg: ServerMessage) -> Result<()> {
         match msg {
+            ServerMessage::Changed { collection, fields: Some(obj), ..} if collection == "stream-notify-logged" => {
+                if obj["eventName"] == "user-status" {
+                    match users::status_event(&obj["args"][0]) {
+                        Some((user, status)) => self.update_presence(user, status),
+                        None => warn!("Could not parse status change: {}", obj),
+                    }
+                }
+            },
             ServerMessage::Changed { fields: Some(obj), ..} => {
 
+                let raw = obj["args"][0].clone();
                 let event: RoomEvent = match serde_json::from_value(obj) {
                     Ok(evt) => evt,
                     Err(e) => {
@@ -388,74 +1861,108 @@ impl Proxy {
                 let remote_host = self.server_addr.clone();
 
                 match (event.args.0, event.args.1) {
-                    ( RoomEventData {t: Some(t), msg, u, ..}
+                    ( RoomEventData {t: Some(t), msg, u, rid, ..}
                     , RoomExtraInfo { room_name: Some(room_name) , room_type, ..})
                         if &t == "room_changed_topic" && (room_type == 'c' || room_type == 'p') => {
                             //ChatEvent::TopicChange { user: u.username, room_name, topic: msg }
-                                let chan = format!("#{}", room_name);
+                                let chan = self.event_channel(&rid, room_type, &room_name).await;
+                                self.members.set_topic(&chan, Some(msg.clone()));
                                 let out = Message { tags: None, prefix: Some(Prefix::Nickname(u.username.clone(), u.username, remote_host)) ,
                                           command: Command::TOPIC(chan, Some(msg))};
-                                self.client_up.send(out).await?;
+                                self.broadcast(out);
                     },
 
-                    ( RoomEventData {t: Some(t), u, ..}
+                    ( RoomEventData {t: Some(t), msg, u, rid, ..}
                     , RoomExtraInfo { room_name: Some(room_name) , room_type, ..})
-                        if &t == "ul" && (room_type == 'c' || room_type == 'p') => {
-                            //ChatEvent::TopicChange { user: u.username, room_name, topic: msg }
-                                let chan = format!("#{}", room_name);
-                                let out = Message { tags: None, prefix: Some(Prefix::Nickname(u.username.clone(), u.username, remote_host)) ,
-                                          command: Command::PART(chan, None)};
-                                self.client_up.send(out).await?;
+                        if ["uj", "ul", "au", "ru"].contains(&&t[..]) && (room_type == 'c' || room_type == 'p') => {
+                                let chan = self.event_channel(&rid, room_type, &room_name).await;
+                                self.handle_membership(&t, chan, u.username, msg).await?;
                     },
 
                     ( red, rei ) => {
 
                         let target = match (rei.room_type, rei.room_name) {
-                            ('c', Some(name)) => format!("#{}", name),
-                            ('p', Some(name)) => format!("#{}", name), //TODO consider using & for private channels ?
-                            ('d', None) => self.clientinfo.nick.to_string(),
+                            (room_type, Some(name)) if room_type == 'c' || room_type == 'p' =>
+                                self.event_channel(&red.rid, room_type, &name).await,
+                            ('d', _) => match self.direct_channel(&red.rid.to_string()).await? {
+                                Some(channel) => channel,
+                                None => self.clientinfo.nick.to_string(),
+                            },
                             _ => { error!("Incorrect data in RoomExtraInfo"); return Ok(()) },
                         };
 
-                        let is_new_message = self.session.room_by_id(&red.rid)
-                            .map_or(false, |room| room.is_timestamp_fresh(red.ts));
+                        if red.t.as_deref() == Some("rm") {
+                            let by = raw["editedBy"]["username"].as_str().map(str::to_string);
+                            self.show_deletion(&red.id, Some(target), by);
+                            return Ok(())
+                        }
+
+                        let is_new_message = match self.session.room_by_id(&red.rid) {
+                            Some(room) => room.is_timestamp_fresh(red.ts),
+                            // rooms opened since we logged in, such as new direct messages
+                            None => self.texts.get(&red.id).is_none() && raw["ts"]["$date"].as_i64()
+                                .map_or(false, |ts| Utc::now().timestamp_millis() - ts < 60_000),
+                        };
+
+                        let user = red.u.username.clone();
+                        let from = Some(Prefix::Nickname(user.clone(), user, remote_host));
 
                         if is_new_message {
 
+                            // recorded for our own messages too, so that they can be
+                            // quoted and replied to
+                            self.texts.insert(red.id.clone(), (target.clone(), red.msg.clone()));
+                            self.reactions.insert(red.id.clone(), reactions::parse(&raw));
+
+                            if let Some(tmid) = raw["tmid"].as_str() {
+                                self.threads.insert(red.id.clone(), tmid.to_string());
+                            }
+
                             if self.message_cache.sent(&red.id) {
                                 debug!("Message {:?} sent by us, ignoring", red.id);
                                 return Ok(())
                             }
 
-                            let user = red.u.username;
-                            let from = Some(Prefix::Nickname(user.clone(), user, remote_host));
-
                             if red.msg.trim().len() > 0 {
-                                let out = Message { tags: None, prefix: from.clone(),
-                                    command: Command::PRIVMSG(target.clone(), red.msg)};
-
-                                self.client_up.send(out).await?;
+                                let mut tags = message_tags(&raw, true).unwrap_or_default();
+                                let (me, text) = match ctcp::me(&red.msg) {
+                                    Some(me) => (true, me),
+                                    None => (false, red.msg.as_str()),
+                                };
+                                let own = red.u.username == self.clientinfo.nick;
+                                let tmid = raw["tmid"].as_str();
+                                let (text, plain) = self.compose(self.render(text), me, own, tmid);
+                                if let Some(tmid) = tmid {
+                                    tags.push(Tag("+draft/reply".into(), Some(tmid.into())));
+                                }
+                                self.broadcast_text(tags, from.clone(), &target,
+                                    |caps| if caps.has("message-tags") { text.clone() } else { plain.clone() });
                             }
 
-                            for file in red.attachments {
-
-                                let action = match (file.title, file.image_url) {
-                                    (Some(title), Some(url)) => format!("[{}]({})", title, url),
-                                    (Some(title), None) => format!("[{}]", title),
-                                    (None, Some(url)) => url,
-                                    (None, None) => format!("<UNKNOWN ATTACHMENT>"),
-                                };
+                            for file in raw["attachments"].as_array().into_iter().flatten() {
 
+                                let action = embed::describe_attachment(file);
                                 let msg = format!("\x01ACTION {}\x01", action);
 
-                                let out = Message { tags: None, prefix: from.clone(),
+                                let out = Message { tags: message_tags(&raw, false), prefix: from.clone(),
                                     command: Command::PRIVMSG(target.clone(), msg)};
-                                self.client_up.send(out).await?;
+                                self.broadcast(out);
+
+                                if let (Some(art), Some(url)) = (&self.art, file["image_url"].as_str()) {
+                                    self.render_image(art.clone(), url.to_string(), message_tags(&raw, false), from.clone(), target.clone());
+                                }
                             }
 
+                            self.send_previews(&red.id, &raw, &target, &from);
+
+                        } else if self.send_previews(&red.id, &raw, &target, &from) {
+                            debug!("Showed link previews for {:?}", red.id);
+                        } else if self.show_edit(&red.id, &raw, &target) {
+                            debug!("Showed edit of {:?}", red.id);
+                        } else if self.show_reactions(&red.id, &raw, &target) {
+                            debug!("Showed reactions to {:?}", red.id);
                         } else {
-                            warn!("Unhandled reaction for {:?}", red);
-                            //TODO handle reactions
+                            warn!("Unhandled change of {:?}", red);
                         }
 
                     },
@@ -463,11 +1970,14 @@ impl Proxy {
                 };
                 /*
                 if let Some(evt) = ChatEvent::from_room_event(serde_json::from_value(obj.clone())?) {
-                    self.client_up.send(evt.into_irc(&self.clientinfo.nick, self.server_addr.clone())).await?
+                    self.broadcast(evt.into_irc(&self.clientinfo.nick, self.server_addr.clone()))
                 } else {
                     warn!("Unsupported Rocket change: {}", obj);
                 } */
             },
+            ServerMessage::Removed { collection, id } if collection == "stream-room-messages" => {
+                self.show_deletion(&MessageID::from(id.to_string()), None, None);
+            },
             ServerMessage::Updated {..} => {},   // for RPC completion status, irrelevant for us.
             other => {
                 warn!("Unsupported Rocket event: {}", other.pretty());
@@ -481,28 +1991,99 @@ impl Proxy {
 
 impl ProxyListener {
 
-    pub fn new(bind: String, backend: String) -> Self {
-        Self { bind, backend }
+    pub fn new(config: Config) -> Result<Self> {
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let tls = Arc::new(TlsConfig::load(cert.clone(), key.clone())?);
+                tls.reload_on_sighup()?;
+                Some(tls)
+            },
+            _ => None,
+        };
+        Ok(Self { config: Arc::new(config), tls, sessions: Arc::new(Sessions::new()) })
     }
 
     pub async fn run(&self) -> Result<()>  {
-        let listener = TcpListener::bind(&self.bind).await?;
-        info!("Bound to {}", self.bind);
+        let listener = TcpListener::bind(&self.config.bind).await?;
+        info!("Bound to {}{}", self.config.bind, if self.tls.is_some() { " (TLS)" } else { "" });
 
         loop {
             let (sock, peer) = listener.accept().await?;
             info!("Accepted connection from {}", peer);
 
-            let server = self.backend.clone();
-            tokio::spawn(async move {
-                match Proxy::run(sock, peer, server).await {
-                    Err(e) => error!("Connection terminated with error: {:?}", e),
-                    _ => ()
-                }
-            });
+            let (config, sessions) = (self.config.clone(), self.sessions.clone());
+            match &self.tls {
+                None => spawn_client(sock, peer, config, sessions),
+                Some(tls) => {
+                    let acceptor = tls.acceptor();
+                    tokio::spawn(async move {
+                        match acceptor.accept(sock).await {
+                            Ok(sock) => spawn_client(sock, peer, config, sessions),
+                            Err(e) => warn!("TLS handshake with {} failed: {}", peer, e),
+                        }
+                    });
+                },
+            }
         }
 
     }
 
 }
 
+/// Handle one IRC connection: registration, then attachment to a Rocket
+/// session, either new or (in bouncer mode) already running.
+async fn serve<S>(sock: S, peer: SocketAddr, config: Arc<Config>, sessions: Arc<Sessions>) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut client = Codec::new("utf8")?
+        .framed(sock);
+
+    let (clientinfo, session) = login(&mut client, peer.ip().to_string(), &config, &sessions).await?;
+    debug!("Client identified as {}", clientinfo);
+    let session = session.events;
+
+    let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::unbounded();
+    session.unbounded_send(Event::Attach(Client { id, info: clientinfo, tx, batch: None }))
+        .map_err(|_| anyhow!("Rocket session terminated"))?;
+
+    let (mut client_up, client_down) = client.split();
+    let mut client_down = client_down.fuse();
+
+    let result: Result<()> = loop {
+
+        select! {
+
+            msg = client_down.next() => match msg {
+                None | Some(Ok(Message { command: Command::QUIT(_), .. })) => break Ok(()),
+                Some(Err(e)) => break Err(e.into()),
+                Some(Ok(msg)) => if session.unbounded_send(Event::Client(id, msg)).is_err() {
+                    break Err(anyhow!("Rocket session terminated"))
+                },
+            },
+
+            msg = rx.next() => match msg {
+                Some(msg) => if let Err(e) = client_up.send(msg).await {
+                    break Err(e.into())
+                },
+                None => break Err(anyhow!("Rocket session terminated")),
+            },
+
+        }
+
+    };
+
+    let _ = session.unbounded_send(Event::Detach(id));
+    result
+}
+
+fn spawn_client<S>(sock: S, peer: SocketAddr, config: Arc<Config>, sessions: Arc<Sessions>)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    tokio::spawn(async move {
+        match serve(sock, peer, config, sessions).await {
+            Err(e) => error!("Connection terminated with error: {:?}", e),
+            _ => ()
+        }
+    });
+}
@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::Value;
+
+/// Reactions on a Rocket message: users by emoji shortcode (`:smile:`)
+pub type Reactions = BTreeMap<String, BTreeSet<String>>;
+
+/// Shortcodes Rocket names differently from the emoji database, or
+/// aliases it prefers. Other emoji are looked up with the emojis crate.
+const EMOJI: [(&str, &str); 22] = [
+    (":+1:", "👍"), (":thumbsup:", "👍"), (":-1:", "👎"), (":thumbsdown:", "👎"),
+    (":smile:", "😄"), (":slight_smile:", "🙂"), (":laughing:", "😆"), (":joy:", "😂"),
+    (":heart:", "❤️"), (":tada:", "🎉"), (":eyes:", "👀"), (":white_check_mark:", "✅"),
+    (":x:", "❌"), (":fire:", "🔥"), (":rocket:", "🚀"), (":pray:", "🙏"),
+    (":wave:", "👋"), (":ok_hand:", "👌"), (":thinking:", "🤔"), (":clap:", "👏"),
+    (":cry:", "😢"), (":100:", "💯"),
+];
+
+pub fn parse(message: &Value) -> Reactions {
+    message["reactions"].as_object().into_iter().flatten()
+        .map(|(emoji, reaction)| {
+            let users = reaction["usernames"].as_array().into_iter().flatten()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect();
+            (emoji.clone(), users)
+        })
+        .collect()
+}
+
+/// Reactions added and removed between two states, as (emoji, username, added)
+pub fn diff(old: &Reactions, new: &Reactions) -> Vec<(String, String, bool)> {
+    let empty = BTreeSet::new();
+    let mut changes = vec![];
+    for emoji in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        let (before, after) = (old.get(emoji).unwrap_or(&empty), new.get(emoji).unwrap_or(&empty));
+        changes.extend(after.difference(before).map(|user| (emoji.clone(), user.clone(), true)));
+        changes.extend(before.difference(after).map(|user| (emoji.clone(), user.clone(), false)));
+    }
+    changes
+}
+
+/// Unicode emoji for a shortcode, or the shortcode itself if we don't know
+/// it (custom emoji of the server)
+pub fn to_unicode(shortcode: &str) -> &str {
+    if let Some((_, emoji)) = EMOJI.iter().find(|(code, _)| *code == shortcode) {
+        return emoji
+    }
+    shortcode.strip_prefix(':').and_then(|code| code.strip_suffix(':'))
+        .and_then(emojis::get_by_shortcode)
+        .map_or(shortcode, |emoji| emoji.as_str())
+}
+
+/// Rocket shortcode for an emoji sent by a client, which may already be a shortcode
+pub fn to_shortcode(emoji: &str) -> Option<String> {
+    if emoji.len() > 2 && emoji.starts_with(':') && emoji.ends_with(':') {
+        return Some(emoji.to_string())
+    }
+    if let Some((code, _)) = EMOJI.iter().find(|(_, unicode)| *unicode == emoji) {
+        return Some(code.to_string())
+    }
+    // clients may or may not send the emoji presentation selector
+    emojis::get(emoji).or_else(|| emojis::get(emoji.trim_end_matches('\u{fe0f}')))
+        .and_then(|emoji| emoji.shortcode())
+        .map(|code| format!(":{}:", code))
+}
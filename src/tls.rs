@@ -0,0 +1,69 @@
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+use anyhow::{Result, anyhow};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{TlsAcceptor, rustls::{NoClientAuth, ServerConfig, internal::pemfile}};
+use log::{info, error};
+
+/// Certificate and key used to terminate TLS on the IRC side.
+/// The acceptor is rebuilt from disk on `reload`, so that renewed
+/// certificates can be picked up without dropping existing connections.
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+fn load_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| anyhow!("Could not parse certificate {}", cert.display()))?;
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|_| anyhow!("Could not parse private key {}", key.display()))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| anyhow!("Could not parse private key {}", key.display()))?;
+    }
+    let key = keys.into_iter().next()
+        .ok_or(anyhow!("No private key found in {}", key.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl TlsConfig {
+
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Self> {
+        let acceptor = RwLock::new(load_acceptor(&cert, &key)?);
+        Ok(TlsConfig { cert, key, acceptor })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = load_acceptor(&self.cert, &self.key)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /// Reload the certificate every time the process receives SIGHUP.
+    /// A failed reload keeps the previous certificate in use.
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let tls = self.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match tls.reload() {
+                    Ok(()) => info!("Reloaded TLS certificate from {}", tls.cert.display()),
+                    Err(e) => error!("Could not reload TLS certificate: {:?}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+}
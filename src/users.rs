@@ -0,0 +1,109 @@
+//! Rocket user profiles and presence, for WHOIS, WHO and away-notify
+use anyhow::Result;
+use rasta::Handle;
+use serde_json::{Value, json};
+
+/// Rocket presence states, indexed by their number in `user-status` events
+const STATES: [&str; 4] = ["offline", "online", "away", "busy"];
+
+/// Presence of a user: online, away, busy or offline, and custom status text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub state: String,
+    pub text: String,
+}
+
+impl Status {
+
+    pub fn is_away(&self) -> bool {
+        self.state != "online"
+    }
+
+    /// Away message: the status text, or the state itself. None if online.
+    pub fn away_message(&self) -> Option<String> {
+        if !self.is_away() {
+            None
+        } else if self.text.is_empty() {
+            let mut state = self.state.clone();
+            if let Some(first) = state.get_mut(..1) {
+                first.make_ascii_uppercase();
+            }
+            Some(state)
+        } else {
+            Some(self.text.clone())
+        }
+    }
+
+}
+
+/// Username and new status from the arguments of a `user-status`
+/// event: `[uid, username, state, text]`
+pub fn status_event(args: &Value) -> Option<(String, Status)> {
+    let username = args[1].as_str()?.to_string();
+    let state = STATES.get(args[2].as_u64()? as usize)?.to_string();
+    let text = args[3].as_str().unwrap_or("").to_string();
+    Some((username, Status { state, text }))
+}
+
+/// What we show of a Rocket user
+#[derive(Debug, Clone)]
+pub struct User {
+    pub username: String,
+    /// Display name, used as realname
+    pub name: String,
+    pub status: Status,
+    pub roles: Vec<String>,
+    /// Offset from UTC, in hours
+    pub utc_offset: Option<f64>,
+}
+
+impl User {
+
+    pub fn from_json(user: &Value) -> Option<Self> {
+        let username = user["username"].as_str()?.to_string();
+        Some(User {
+            name: user["name"].as_str().unwrap_or(&username).to_string(),
+            username,
+            status: Status {
+                state: user["status"].as_str().unwrap_or("offline").to_string(),
+                text: user["statusText"].as_str().unwrap_or("").to_string(),
+            },
+            roles: user["roles"].as_array().into_iter().flatten()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            utc_offset: user["utcOffset"].as_f64(),
+        })
+    }
+
+    /// Timezone of the user, like `UTC+2` or `UTC-3:30`
+    pub fn timezone(&self) -> Option<String> {
+        let offset = self.utc_offset?;
+        let minutes = (offset * 60.0).round() as i64;
+        let (hours, minutes) = (minutes.abs() / 60, minutes.abs() % 60);
+        let sign = if offset < 0.0 { '-' } else { '+' };
+        Some(match minutes {
+            0 => format!("UTC{}{}", sign, hours),
+            _ => format!("UTC{}{}:{:02}", sign, hours, minutes),
+        })
+    }
+
+}
+
+/// Profile of a user, by username
+pub async fn info(server: &mut Handle, username: &str) -> Result<Option<User>> {
+    // `username` looks the user up by exact username, unlike `filter` which
+    // searches names and would need the results to be sorted out
+    let result = server.call("getFullUserData", vec![json!({ "username": username })]).await?;
+    Ok(result.as_array().into_iter().flatten()
+        .filter(|user| user["username"].as_str().map_or(false, |name| name.eq_ignore_ascii_case(username)))
+        .find_map(User::from_json))
+}
+
+/// Members of a room, with their status
+pub async fn room_users(server: &mut Handle, rid: &str) -> Result<Vec<User>> {
+    let result = server.call("getUsersOfRoom", vec![json!(rid), json!(true)]).await?;
+    Ok(result["records"].as_array().into_iter().flatten()
+        .filter_map(User::from_json)
+        .collect())
+}
@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+
 pub struct LazyZip<A,B> {
     a: A,
     b: Option<B>,
@@ -32,18 +34,22 @@ impl<T: Eq + Clone> Cache<T> {
 
     pub fn send(&mut self) -> T {
         let id: T = (self.init)();
+        self.insert(id.clone());
+        id
+    }
+
+    pub fn insert(&mut self, id: T) {
         let capa = self.cache.capacity();
 
         if self.cache.len() < capa {
-            self.cache.push(id.clone())
+            self.cache.push(id)
         } else {
-            self.cache[self.idx] = id.clone();
+            self.cache[self.idx] = id;
             self.idx += 1;
             if self.idx >= capa {
                 self.idx = 0;
             }
         }
-        id
     }
 
     pub fn sent(&mut self, id: &T) -> bool {
@@ -54,4 +60,40 @@ impl<T: Eq + Clone> Cache<T> {
         }
         return false;
     }
-}
\ No newline at end of file
+}
+
+/// Bounded map of the latest entries, for per-message state
+/// that only matters for recent messages
+pub struct Recent<K, V> {
+    entries: VecDeque<(K, V)>,
+    capacity: usize,
+}
+
+impl<K: Eq, V> Recent<K, V> {
+
+    pub fn new(capacity: usize) -> Self {
+        Recent { entries: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.entries.retain(|(k, _)| k != &key);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, value));
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=&(K, V)> {
+        self.entries.iter()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.entries.iter().position(|(k, _)| k == key)?;
+        self.entries.remove(idx).map(|(_, v)| v)
+    }
+
+}